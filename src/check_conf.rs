@@ -0,0 +1,992 @@
+use crate::error::DeployerError;
+use crate::generate_conf::{
+    file_struct::{
+        AssetSettings, ConfigFile, EnvironmentSettings, GitHubAppSettings, ProxySettings,
+        ReportSettings, RepositorySettings, Service, SignatureSettings, SignatureSource,
+        SupervisorSettings, TrackSettings, TrackSource, WebhookSettings,
+    },
+    jsonc,
+};
+use crate::run_deployer::{
+    provider::{self, git, github_app::Installation, GitHub, SourceProvider, PROVIDERS},
+    pull::{build::release_dir, changes::Watch, checks::CheckSource, tags},
+};
+use crate::secrets;
+use reqwest::{Client, StatusCode};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub level: Level,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.level {
+            Level::Error => write!(f, "error: {}", self.message),
+            Level::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+fn error(message: String) -> Problem {
+    Problem {
+        level: Level::Error,
+        message,
+    }
+}
+
+fn warning(message: String) -> Problem {
+    Problem {
+        level: Level::Warning,
+        message,
+    }
+}
+
+/// `deployer check`. Print every problem of the config
+/// file at `path` and fail if there is at least one error.
+/// With `online` the repository and token are checked
+/// against GitHub as well.
+pub async fn check(path: &str, online: bool) -> Result<(), DeployerError> {
+    let mut problems = Vec::new();
+    let config = match fs::read_to_string(path) {
+        Ok(data) => parse(&data, &mut problems),
+        Err(source) => {
+            return Err(DeployerError::Io {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    };
+    if let Some(config) = &config {
+        problems.extend(diagnose(config));
+        if online && !problems.iter().any(|p| p.level == Level::Error) {
+            problems.extend(check_online(config).await);
+        }
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let errors = problems.iter().filter(|p| p.level == Level::Error).count();
+    let warnings = problems.len() - errors;
+    println!("{} error(s), {} warning(s)", errors, warnings);
+    if errors > 0 {
+        return Err(DeployerError::Config {
+            path: path.to_owned(),
+            message: format!("{} error(s) found", errors),
+        });
+    }
+    Ok(())
+}
+
+/// Parse config and report syntax errors, unknown fields,
+/// unset environment variables and values of the wrong type.
+fn parse(data: &str, problems: &mut Vec<Problem>) -> Option<ConfigFile> {
+    let mut value: Value = match jsonc::parse(data) {
+        Ok(value) => value,
+        Err(e) => {
+            problems.push(error(e.to_string()));
+            return None;
+        }
+    };
+    unknown_fields(&value, &schema(), "", problems);
+    let missing = missing_fields(&value, problems);
+    if let Err(errors) = secrets::interpolate(&mut value) {
+        problems.extend(errors.into_iter().map(error));
+        return None;
+    }
+    match serde_path_to_error::deserialize::<_, ConfigFile>(value) {
+        Ok(mut config) => {
+            if let Err(e) = config.normalise() {
+                problems.push(error(e));
+                return None;
+            }
+            if let Err(e) = secrets::resolve_tokens(&mut config) {
+                problems.push(error(e));
+            }
+            Some(config)
+        }
+        Err(e) => {
+            // serde stops at the first missing field, all of them are reported above.
+            if !(missing && e.inner().to_string().starts_with("missing field")) {
+                problems.push(error(format!("{}: {}", e.path(), e.inner())));
+            }
+            None
+        }
+    }
+}
+
+/// Fields without a default value. `branch` is
+/// required only without `environments` and `track`.
+const REQUIRED: [&str; 4] = ["repository", "branch", "pull_dir", "services"];
+const REQUIRED_ENVIRONMENT: [&str; 2] = ["name", "branch"];
+const REQUIRED_SERVICE: [&str; 3] = ["name", "root_dir", "build_dir"];
+
+/// Report every missing required field. Returns
+/// whether anything was missing.
+fn missing_fields(value: &Value, problems: &mut Vec<Problem>) -> bool {
+    let before = problems.len();
+    match value.get("repositories").and_then(Value::as_array) {
+        Some(repositories) => {
+            for (i, repository) in repositories.iter().enumerate() {
+                require_repository(repository, &format!("repositories[{}].", i), problems);
+            }
+        }
+        None => require_repository(value, "", problems),
+    }
+    problems.len() > before
+}
+
+fn require_repository(value: &Value, path: &str, problems: &mut Vec<Problem>) {
+    let mut require = |object: &Value, fields: &[&str], path: &str| {
+        for field in fields {
+            if object.get(field).is_none() {
+                problems.push(error(format!("{}{}: missing field", path, field)));
+            }
+        }
+    };
+    let without_branch = |fields: &[&'static str], object: &Value, skip: bool| {
+        let skip = skip || object.get("track").is_some();
+        fields
+            .iter()
+            .filter(|f| **f != "branch" || !skip)
+            .copied()
+            .collect::<Vec<_>>()
+    };
+    let environments = value.get("environments").and_then(Value::as_array);
+    if value.is_object() {
+        let required = without_branch(&REQUIRED, value, environments.is_some());
+        require(value, &required, path);
+    }
+    for (i, environment) in environments.into_iter().flatten().enumerate() {
+        if environment.is_object() {
+            require(
+                environment,
+                &without_branch(&REQUIRED_ENVIRONMENT, environment, false),
+                &format!("{}environments[{}].", path, i),
+            );
+        }
+    }
+    if let Some(services) = value.get("services").and_then(Value::as_array) {
+        for (i, service) in services.iter().enumerate() {
+            if service.is_object() {
+                require(
+                    service,
+                    &REQUIRED_SERVICE,
+                    &format!("{}services[{}].", path, i),
+                );
+            }
+        }
+    }
+}
+
+/// Config with every optional section filled in,
+/// used as the list of known fields.
+fn schema() -> Value {
+    let service = || Service {
+        command: Some(String::new()),
+        proxy: Some(ProxySettings::default()),
+        env: BTreeMap::from([(String::new(), String::new())]),
+        asset: Some(AssetSettings::default()),
+        watch_paths: vec![String::new()],
+        ..Service::default()
+    };
+    let track = || {
+        Some(TrackSettings {
+            source: TrackSource::Tags,
+            pattern: Some(String::new()),
+            version: Some(String::new()),
+            prereleases: false,
+        })
+    };
+    let report = || ReportSettings {
+        environment: Some(String::new()),
+        log_url: Some(String::new()),
+        commit_status: false,
+    };
+    let signatures = || SignatureSettings {
+        source: SignatureSource::Local,
+        allowed_signers: Some(String::new()),
+        gpg_keyring: Some(String::new()),
+    };
+    let environment = || EnvironmentSettings {
+        name: String::new(),
+        branch: String::new(),
+        track: track(),
+        build_root: Some(String::new()),
+        env: BTreeMap::from([(String::new(), String::new())]),
+        unit: String::new(),
+        port_offset: 0,
+    };
+    let config = ConfigFile {
+        track: track(),
+        token_env: Some(String::new()),
+        token_file: Some(String::new()),
+        services: vec![service()],
+        environments: vec![environment()],
+        provider: Some(String::new()),
+        base_url: Some(String::new()),
+        github_app: Some(GitHubAppSettings::default()),
+        username: Some(String::new()),
+        ssh_key: Some(String::new()),
+        required_checks: vec![String::new()],
+        report: Some(report()),
+        signatures: Some(signatures()),
+        repositories: vec![RepositorySettings {
+            base_url: Some(String::new()),
+            github_app: Some(GitHubAppSettings::default()),
+            required_checks: vec![String::new()],
+            report: Some(report()),
+            signatures: Some(signatures()),
+            username: Some(String::new()),
+            ssh_key: Some(String::new()),
+            track: track(),
+            token_env: Some(String::new()),
+            token_file: Some(String::new()),
+            services: vec![service()],
+            environments: vec![environment()],
+            ..RepositorySettings::default()
+        }],
+        history_file: Some(String::new()),
+        supervisor: Some(SupervisorSettings::default()),
+        webhook: Some(WebhookSettings::default()),
+        ..ConfigFile::default()
+    };
+    serde_json::to_value(config).unwrap_or_default()
+}
+
+fn unknown_fields(value: &Value, schema: &Value, path: &str, problems: &mut Vec<Problem>) {
+    match (value, schema) {
+        (Value::Object(fields), Value::Object(known)) => {
+            for (key, value) in fields {
+                let field = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match known.get(key) {
+                    // Maps with free-form keys.
+                    Some(_) if key == "env" => {}
+                    Some(schema) => unknown_fields(value, schema, &field, problems),
+                    None => problems.push(error(format!("{}: unknown field", field))),
+                }
+            }
+        }
+        (Value::Array(items), Value::Array(known)) => {
+            if let Some(schema) = known.first() {
+                for (i, item) in items.iter().enumerate() {
+                    unknown_fields(item, schema, &format!("{}[{}]", path, i), problems);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Everything that can be checked without network.
+/// `run` refuses to start if any of these is an error.
+/// Expects a normalised config.
+pub fn diagnose(config: &ConfigFile) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    if config.repositories.is_empty() {
+        problems.push(error(
+            "repositories: Not a single repository specified".to_owned(),
+        ));
+    }
+    let mut ids = HashSet::new();
+    let mut names = HashSet::new();
+    let mut listens = HashSet::new();
+    for (i, repository) in config.repositories.iter().enumerate() {
+        if !ids.insert(repository.id()) {
+            let field = match repository.track {
+                Some(_) => "track",
+                None => "branch",
+            };
+            problems.push(error(format!(
+                "{}: {} is already watched",
+                config.field(i, field),
+                repository.id()
+            )));
+        }
+        diagnose_repository(config, i, &mut names, &mut listens, &mut problems);
+    }
+    check_overlaps(config, &mut problems);
+
+    if let Some(supervisor) = &config.supervisor {
+        if !Path::new(&supervisor.log_dir).is_absolute() {
+            problems.push(error(format!(
+                "supervisor.log_dir: \"{}\" is not an absolute path",
+                supervisor.log_dir
+            )));
+        }
+    }
+    if let Some(webhook) = &config.webhook {
+        if webhook.secret.is_empty() {
+            problems.push(error("webhook.secret: is not specified".to_owned()));
+        }
+        if !webhook.path.starts_with('/') {
+            problems.push(error(format!(
+                "webhook.path: \"{}\" must start with /",
+                webhook.path
+            )));
+        }
+    }
+    problems
+}
+
+/// Service names and proxy addresses are
+/// unique across all repositories.
+fn diagnose_repository<'a>(
+    config: &'a ConfigFile,
+    index: usize,
+    names: &mut HashSet<&'a str>,
+    listens: &mut HashSet<&'a str>,
+    problems: &mut Vec<Problem>,
+) {
+    let repository = &config.repositories[index];
+    let field = |name: &str| config.field(index, name);
+
+    let token_missing = repository.token.is_empty() || repository.token == "YOUR-GITHUB-TOKEN-HERE";
+    if token_missing
+        && repository.github_app.is_none()
+        && provider::needs_token(&repository.provider)
+    {
+        problems.push(error(format!(
+            "{}: token for provider \"{}\" is not specified, set token, token_env or token_file",
+            field("token"),
+            repository.provider
+        )));
+    }
+    if repository.repository.is_empty()
+        || repository.repository == "github.com/your-repository/link"
+    {
+        problems.push(error(format!(
+            "{}: Github repository is not specified",
+            field("repository")
+        )));
+    } else if !PROVIDERS.contains(&repository.provider.as_str()) {
+        problems.push(error(format!(
+            "{}: unknown provider \"{}\"",
+            field("provider"),
+            repository.provider
+        )));
+    } else {
+        match provider::new(repository) {
+            Ok(provider) => {
+                let reports = [CheckSource::CheckRuns, CheckSource::Statuses]
+                    .iter()
+                    .any(|source| provider.checks_url("", *source).is_some());
+                if !repository.required_checks.is_empty() && !reports {
+                    problems.push(error(format!(
+                        "{}: provider \"{}\" doesn't report checks",
+                        field("required_checks"),
+                        repository.provider
+                    )));
+                }
+            }
+            Err(e) => problems.push(error(format!("{}: {}", field("repository"), e))),
+        }
+    }
+    let github_signatures = repository
+        .signatures
+        .as_ref()
+        .is_some_and(|s| s.source == SignatureSource::Github);
+    for (name, set) in [
+        ("github_app", repository.github_app.is_some()),
+        ("report", repository.report.is_some()),
+        ("signatures.source", github_signatures),
+    ] {
+        if set && repository.provider != "github" {
+            problems.push(error(format!(
+                "{}: only works with provider \"github\"",
+                field(name)
+            )));
+        }
+    }
+    if let Some(signatures) = &repository.signatures {
+        let files = [
+            ("signatures.allowed_signers", &signatures.allowed_signers),
+            ("signatures.gpg_keyring", &signatures.gpg_keyring),
+        ];
+        if signatures.source == SignatureSource::Local && files.iter().all(|(_, f)| f.is_none()) {
+            problems.push(error(format!(
+                "{}: set allowed_signers or gpg_keyring to verify signatures",
+                field("signatures")
+            )));
+        }
+        for (name, file) in files {
+            if let Some(file) = file.as_deref().filter(|f| !Path::new(f).is_file()) {
+                problems.push(error(format!(
+                    "{}: \"{}\" does not exist",
+                    field(name),
+                    file
+                )));
+            }
+        }
+    }
+    if let Some(base_url) = &repository.base_url {
+        if !base_url.starts_with("https://") && !base_url.starts_with("http://") {
+            problems.push(error(format!(
+                "{}: \"{}\" must start with https:// or http://",
+                field("base_url"),
+                base_url
+            )));
+        }
+    }
+    match &repository.track {
+        Some(track) => {
+            if let Err(e) = tags::Filter::new(track) {
+                problems.push(error(format!("{}: {}", field("track"), e)));
+            }
+            if track.source == TrackSource::Releases && repository.provider == "git" {
+                problems.push(error(format!(
+                    "{}: releases can't be tracked with provider \"git\"",
+                    field("track")
+                )));
+            }
+        }
+        None if repository.branch.is_empty() => {
+            problems.push(error(format!(
+                "{}: No main branch specified",
+                field("branch")
+            )));
+        }
+        None => {}
+    }
+    if repository.poll_interval == 0 {
+        problems.push(error(format!(
+            "{}: must be at least 1 second",
+            field("poll_interval")
+        )));
+    }
+    check_dir(&field("pull_dir"), &repository.pull_dir, problems);
+
+    if repository.services.is_empty() {
+        problems.push(error(format!(
+            "{}: Not a single service specified",
+            field("services")
+        )));
+    }
+    for (i, service) in repository.services.iter().enumerate() {
+        let field = |name: &str| field(&format!("services[{}].{}", i, name));
+        if service.name.is_empty() {
+            problems.push(error(format!("{}: is empty", field("name"))));
+        } else if !names.insert(service.name.as_str()) {
+            problems.push(error(format!(
+                "{}: duplicate service name \"{}\"",
+                field("name"),
+                service.name
+            )));
+        }
+
+        if !Path::new(&service.root_dir).is_absolute() {
+            problems.push(error(format!(
+                "{}: \"{}\" is not an absolute path",
+                field("root_dir"),
+                service.root_dir
+            )));
+        } else if !Path::new(&service.root_dir).starts_with(&repository.pull_dir) {
+            problems.push(error(format!(
+                "{}: \"{}\" is outside of pull_dir \"{}\"",
+                field("root_dir"),
+                service.root_dir,
+                repository.pull_dir
+            )));
+        }
+        check_dir(&field("build_dir"), &service.build_dir, problems);
+        if let Err(e) = Watch::new(PathBuf::new(), &service.watch_paths) {
+            problems.push(error(format!("{}: {}", field("watch_paths"), e)));
+        }
+
+        if let Some(asset) = &service.asset {
+            let releases = repository.track.as_ref().map(|t| t.source);
+            if releases != Some(TrackSource::Releases) {
+                problems.push(error(format!(
+                    "{}: needs a repository that tracks releases",
+                    field("asset")
+                )));
+            }
+            if asset.name.is_empty() || asset.checksums.is_empty() {
+                problems.push(error(format!(
+                    "{}: name and checksums must be set",
+                    field("asset")
+                )));
+            }
+        }
+
+        if let Some(proxy) = &service.proxy {
+            if service.command.is_none() || config.supervisor.is_none() {
+                problems.push(error(format!(
+                    "{}: needs a command and the supervisor section",
+                    field("proxy")
+                )));
+            }
+            if proxy.ports[0] == proxy.ports[1] {
+                problems.push(error(format!(
+                    "{}: must be different",
+                    field("proxy.ports")
+                )));
+            }
+            if !listens.insert(proxy.listen.as_str()) {
+                problems.push(error(format!(
+                    "{}: \"{}\" is used by another service",
+                    field("proxy.listen"),
+                    proxy.listen
+                )));
+            }
+        } else if service.command.is_some() && config.supervisor.is_none() {
+            problems.push(warning(format!(
+                "{}: is ignored without the supervisor section",
+                field("command")
+            )));
+        }
+    }
+}
+
+fn check_dir(field: &str, dir: &str, problems: &mut Vec<Problem>) {
+    let path = Path::new(dir);
+    if !path.is_absolute() {
+        problems.push(error(format!(
+            "{}: \"{}\" is not an absolute path",
+            field, dir
+        )));
+    } else if !path.is_dir() {
+        problems.push(error(format!("{}: \"{}\" does not exist", field, dir)));
+    }
+}
+
+/// Releases are replaced with `rm -rf`, so a release must
+/// not contain the release or `build_dir` of another service.
+fn check_overlaps(config: &ConfigFile, problems: &mut Vec<Problem>) {
+    let services: Vec<(String, &Service)> = config
+        .repositories
+        .iter()
+        .enumerate()
+        .flat_map(|(i, r)| {
+            r.services
+                .iter()
+                .enumerate()
+                .map(move |(j, s)| (config.field(i, &format!("services[{}].build_dir", j)), s))
+        })
+        .collect();
+    for (i, (_, a)) in services.iter().enumerate() {
+        if services[..i].iter().any(|(_, s)| s.name == a.name) {
+            // Duplicate name, already reported.
+            continue;
+        }
+        let release = release_dir(Path::new(&a.build_dir), &a.name);
+        for (j, (field, b)) in services.iter().enumerate() {
+            if i != j && a.name != b.name && Path::new(&b.build_dir).starts_with(&release) {
+                problems.push(error(format!(
+                    "{}: \"{}\" is inside the release of \"{}\" ({})",
+                    field,
+                    b.build_dir,
+                    a.name,
+                    release.display()
+                )));
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RepositoryResponse {
+    private: bool,
+}
+
+/// Check every repository online.
+async fn check_online(config: &ConfigFile) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for index in 0..config.repositories.len() {
+        problems.extend(check_repository_online(config, index).await);
+    }
+    problems
+}
+
+/// Check that the token works and can read the
+/// repository and that the branch exists.
+async fn check_repository_online(config: &ConfigFile, index: usize) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let repository = &config.repositories[index];
+    if repository.provider != "github" {
+        return check_provider_online(config, index).await;
+    }
+    let field = |name: &str| config.field(index, name);
+    let client = Client::new();
+    let token = match Installation::default().token(repository, &client).await {
+        Ok(token) => token,
+        Err(e) => {
+            problems.push(error(format!("{}: {}", field("github_app"), e)));
+            return problems;
+        }
+    };
+    let repository = &RepositorySettings {
+        token,
+        ..repository.clone()
+    };
+    let github = match GitHub::new(repository) {
+        Ok(github) => github,
+        Err(_) => return problems,
+    };
+    let get = |url: String| github.authorize(client.get(url)).send();
+
+    let res = match get(github.api()).await {
+        Ok(res) => res,
+        Err(e) => {
+            problems.push(error(format!("Failed to reach GitHub: {}", e)));
+            return problems;
+        }
+    };
+    match res.status() {
+        StatusCode::UNAUTHORIZED => {
+            problems.push(error(format!(
+                "{}: GitHub rejected the token",
+                field("token")
+            )));
+            return problems;
+        }
+        StatusCode::NOT_FOUND => {
+            problems.push(error(format!(
+                "{}: {} does not exist or the token can't read it",
+                field("repository"),
+                github.full_name()
+            )));
+            return problems;
+        }
+        status if !status.is_success() => {
+            problems.push(error(format!(
+                "{}: GitHub answered with {}",
+                field("repository"),
+                status
+            )));
+            return problems;
+        }
+        _ => {}
+    }
+    // Only classic tokens report their scopes.
+    let scopes = res
+        .headers()
+        .get("X-OAuth-Scopes")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .map(|s| s.trim().to_owned())
+                .collect::<Vec<_>>()
+        });
+    let body = res.text().await.unwrap_or_default();
+    let response = serde_json::from_str::<RepositoryResponse>(&body);
+    if let (Some(scopes), Ok(response)) = (scopes, response) {
+        if response.private && !scopes.iter().any(|s| s == "repo") {
+            problems.push(error(format!(
+                "{}: needs the \"repo\" scope to read a private repository",
+                field("token")
+            )));
+        }
+    }
+
+    if repository.track.is_some() {
+        return problems;
+    }
+    let url = format!("{}/branches/{}", github.api(), repository.branch);
+    match get(url).await {
+        Ok(res) if res.status() == StatusCode::NOT_FOUND => problems.push(error(format!(
+            "{}: \"{}\" does not exist",
+            field("branch"),
+            repository.branch
+        ))),
+        Ok(res) if !res.status().is_success() => problems.push(error(format!(
+            "{}: GitHub answered with {}",
+            field("branch"),
+            res.status()
+        ))),
+        Ok(_) => {}
+        Err(e) => problems.push(error(format!("Failed to reach GitHub: {}", e))),
+    }
+    problems
+}
+
+/// Ask providers other than GitHub for what is polled:
+/// the commit of the branch or the tags or releases.
+async fn check_provider_online(config: &ConfigFile, index: usize) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let repository = &config.repositories[index];
+    let field = |name: &str| config.field(index, name);
+    let provider = match provider::new(repository) {
+        Ok(provider) => provider,
+        Err(_) => return problems,
+    };
+    let url = match provider::latest_url(
+        provider.as_ref(),
+        &repository.branch,
+        repository.track.as_ref(),
+    ) {
+        Ok(url) => url,
+        Err(_) => return problems,
+    };
+    if let Some(refs) = provider.refs() {
+        let listed = tokio::task::spawn_blocking(move || refs.list()).await;
+        let refs = match listed {
+            Ok(Ok(refs)) => refs,
+            Ok(Err(e)) => {
+                problems.push(error(format!("{}: {}", field("repository"), e)));
+                return problems;
+            }
+            Err(_) => return problems,
+        };
+        if let Err(e) = git::latest(&refs, &repository.branch, repository.track.as_ref()) {
+            problems.push(error(format!("{}: {}", field("repository"), e)));
+        }
+        return problems;
+    }
+    match provider.authorize(Client::new().get(&url)).send().await {
+        Ok(res) if res.status() == StatusCode::UNAUTHORIZED => problems.push(error(format!(
+            "{}: {} rejected the token",
+            field("token"),
+            repository.provider
+        ))),
+        Ok(res) if res.status() == StatusCode::NOT_FOUND => problems.push(error(format!(
+            "{}: {} or its branch does not exist or the token can't read it",
+            field("repository"),
+            repository.repository
+        ))),
+        Ok(res) if !res.status().is_success() => problems.push(error(format!(
+            "{}: {} answered with {}",
+            field("repository"),
+            repository.provider,
+            res.status()
+        ))),
+        Ok(_) => {}
+        Err(e) => problems.push(error(format!("Failed to reach {}: {}", url, e))),
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_deployer::stand_in::StandIn;
+
+    fn messages(problems: &[Problem]) -> Vec<String> {
+        problems.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_unknown_and_mistyped_fields() {
+        let data = r#"{
+            "repository": "github.com/Makefolder/deployer",
+            "branch": "main",
+            "token": "token",
+            "pull_dir": "/tmp",
+            "pull_interval": 30,
+            "services": [{ "name": "api", "root_dir": "/tmp/a", "build_dir": 5, "cmd": "" }],
+        }"#;
+        let mut problems = Vec::new();
+        assert!(parse(data, &mut problems).is_none());
+        assert_eq!(
+            messages(&problems),
+            [
+                "error: pull_interval: unknown field",
+                "error: services[0].cmd: unknown field",
+                "error: services[0].build_dir: invalid type: integer `5`, expected a string",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let data = r#"{ "repository": "github.com/a/b", "services": [{ "name": "api" }] }"#;
+        let mut problems = Vec::new();
+        assert!(parse(data, &mut problems).is_none());
+        assert_eq!(
+            messages(&problems),
+            [
+                "error: branch: missing field",
+                "error: pull_dir: missing field",
+                "error: services[0].root_dir: missing field",
+                "error: services[0].build_dir: missing field",
+            ]
+        );
+
+        // Tags are deployed instead of a branch.
+        let data = r#"{ "repository": "github.com/a/b", "track": { "source": "tags" },
+                        "pull_dir": "/tmp", "services": [] }"#;
+        let mut problems = Vec::new();
+        assert!(parse(data, &mut problems).is_some());
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn test_diagnose_reports_everything() {
+        let service = |name: &str, root_dir: &str, build_dir: &str| Service {
+            name: name.to_owned(),
+            root_dir: root_dir.to_owned(),
+            build_dir: build_dir.to_owned(),
+            ..Service::default()
+        };
+        let mut config = ConfigFile {
+            pull_dir: "/tmp".to_owned(),
+            services: vec![
+                service("deployer-check-api", "/tmp/repo/api", "/tmp"),
+                service("deployer-check-api", "relative/path", "/tmp"),
+                Service {
+                    watch_paths: vec!["shared/[".to_owned()],
+                    ..service("web", "/srv/web", "/tmp/deployer-check-api")
+                },
+            ],
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        assert_eq!(
+            messages(&diagnose(&config)),
+            [
+                "error: token: token for provider \"github\" is not specified, set token, \
+                 token_env or token_file",
+                "error: repository: Github repository is not specified",
+                "error: services[1].name: duplicate service name \"deployer-check-api\"",
+                "error: services[1].root_dir: \"relative/path\" is not an absolute path",
+                "error: services[2].root_dir: \"/srv/web\" is outside of pull_dir \"/tmp\"",
+                "error: services[2].build_dir: \"/tmp/deployer-check-api\" does not exist",
+                "error: services[2].watch_paths: invalid pattern \"shared/[\": Pattern syntax \
+                 error near position 7: invalid range pattern",
+                "error: services[2].build_dir: \"/tmp/deployer-check-api\" is inside the release \
+                 of \"deployer-check-api\" (/tmp/deployer-check-api)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diagnose_repositories() {
+        let repository = |repository: &str, provider: &str, name: &str| RepositorySettings {
+            repository: repository.to_owned(),
+            provider: provider.to_owned(),
+            token: "token".to_owned(),
+            pull_dir: "/tmp".to_owned(),
+            services: vec![Service {
+                name: name.to_owned(),
+                root_dir: "/tmp/repo".to_owned(),
+                build_dir: "/tmp".to_owned(),
+                ..Service::default()
+            }],
+            ..RepositorySettings::default()
+        };
+        let mut config = ConfigFile {
+            repository: String::new(),
+            branch: String::new(),
+            token: String::new(),
+            pull_dir: String::new(),
+            services: Vec::new(),
+            repositories: vec![
+                repository("github.com/a/api", "github", "api"),
+                repository("github.com/a/web", "bitbucket", "api"),
+                repository("github.com/a/api", "github", "worker"),
+                RepositorySettings {
+                    token: String::new(),
+                    ..repository("gitlab.example.com/team/api", "gitlab", "gitlab-api")
+                },
+                RepositorySettings {
+                    token: String::new(),
+                    track: Some(TrackSettings {
+                        source: TrackSource::Releases,
+                        pattern: None,
+                        version: None,
+                        prereleases: false,
+                    }),
+                    required_checks: vec!["build".to_owned()],
+                    signatures: Some(SignatureSettings {
+                        source: SignatureSource::Github,
+                        allowed_signers: None,
+                        gpg_keyring: Some("/nonexistent/trusted.gpg".to_owned()),
+                    }),
+                    ..repository("git@git.example.com:team/api.git", "git", "git-api")
+                },
+            ],
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        assert_eq!(
+            messages(&diagnose(&config)),
+            [
+                "error: repositories[1].provider: unknown provider \"bitbucket\"",
+                "error: repositories[1].services[0].name: duplicate service name \"api\"",
+                "error: repositories[2].branch: github.com/a/api@main is already watched",
+                "error: repositories[3].token: token for provider \"gitlab\" is not specified, \
+                 set token, token_env or token_file",
+                "error: repositories[4].required_checks: provider \"git\" doesn't report checks",
+                "error: repositories[4].signatures.source: only works with provider \"github\"",
+                "error: repositories[4].signatures.gpg_keyring: \"/nonexistent/trusted.gpg\" \
+                 does not exist",
+                "error: repositories[4].track: releases can't be tracked with provider \"git\"",
+            ]
+        );
+
+        let data = r#"{
+            "repository": "github.com/a/b",
+            "repositories": [{ "repository": "github.com/a/c", "branch": "main",
+                               "pull_dir": "/tmp", "services": [] }],
+        }"#;
+        let mut problems = Vec::new();
+        assert!(parse(data, &mut problems).is_none());
+        assert_eq!(
+            messages(&problems),
+            ["error: repositories: can't be used together with top-level repository"]
+        );
+
+        // Would apply to no repository at all.
+        let data = r#"{
+            "token_env": "GITHUB_TOKEN",
+            "poll_interval": 30,
+            "directives": "off",
+            "repositories": [{ "repository": "github.com/a/c", "branch": "main",
+                               "pull_dir": "/tmp", "services": [] }],
+        }"#;
+        let mut problems = Vec::new();
+        assert!(parse(data, &mut problems).is_none());
+        assert_eq!(
+            messages(&problems),
+            [
+                "error: repositories: can't be used together with top-level \
+              token_env, directives, poll_interval"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_enterprise_server_online() {
+        let ghe = StandIn::start(vec![(
+            "/api/v3/repos/team/api",
+            200,
+            br#"{ "private": false }"#.to_vec(),
+        )])
+        .await;
+        let mut config = ConfigFile {
+            repository: "ghe.corp/team/api".to_owned(),
+            base_url: Some(ghe.url.clone()),
+            token: "token".to_owned(),
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        assert_eq!(
+            messages(&check_online(&config).await),
+            ["error: branch: \"main\" does not exist"]
+        );
+        let requests = ghe.requests();
+        assert!(requests[1].starts_with("GET /api/v3/repos/team/api/branches/main "));
+        assert!(requests[0].contains("authorization: token token"));
+    }
+}
@@ -0,0 +1,99 @@
+use std::{error::Error, fmt::Display, io};
+
+/// Errors Deployer can stop with. Every variant
+/// has its own process exit code, see `exit_code`.
+#[derive(Debug)]
+pub enum DeployerError {
+    /// Config file is missing, malformed or incomplete.
+    Config { path: String, message: String },
+    /// Repository provider (GitHub) rejected a request
+    /// or answered with something unexpected.
+    Provider {
+        url: String,
+        status: Option<u16>,
+        message: String,
+    },
+    Git { url: String, source: git2::Error },
+    Build { service: String, message: String },
+    Deploy { service: String, message: String },
+    Io { path: String, source: io::Error },
+}
+
+impl DeployerError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config { .. } => 2,
+            Self::Provider { .. } => 3,
+            Self::Git { .. } => 4,
+            Self::Build { .. } => 5,
+            Self::Deploy { .. } => 6,
+            Self::Io { .. } => 7,
+        }
+    }
+}
+
+impl Error for DeployerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Git { source, .. } => Some(source),
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Display for DeployerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config { path, message } => {
+                write!(f, "Invalid configuration ({path}): {message}")
+            }
+            Self::Provider {
+                url,
+                status: Some(status),
+                message,
+            } => write!(f, "Request to {url} failed with HTTP {status}: {message}"),
+            Self::Provider {
+                url,
+                status: None,
+                message,
+            } => write!(f, "Request to {url} failed: {message}"),
+            Self::Git { url, source } => {
+                write!(f, "Git operation on {url} failed: {}", source.message())
+            }
+            Self::Build { service, message } => {
+                write!(f, "Failed to build service \"{service}\": {message}")
+            }
+            Self::Deploy { service, message } => {
+                write!(f, "Failed to deploy service \"{service}\": {message}")
+            }
+            Self::Io { path, source } => write!(f, "{path}: {source}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let e = DeployerError::Provider {
+            url: "https://api.github.com/repos/a/b/commits/main".to_owned(),
+            status: Some(401),
+            message: "Bad credentials".to_owned(),
+        };
+        assert_eq!(
+            e.to_string(),
+            "Request to https://api.github.com/repos/a/b/commits/main failed with HTTP 401: Bad credentials"
+        );
+        assert_eq!(e.exit_code(), 3);
+
+        let e = DeployerError::Io {
+            path: "/var/www".to_owned(),
+            source: io::Error::new(io::ErrorKind::NotFound, "directory does not exist"),
+        };
+        assert_eq!(e.to_string(), "/var/www: directory does not exist");
+        assert_eq!(e.exit_code(), 7);
+    }
+}
@@ -7,6 +7,11 @@ use std::{
 };
 
 pub mod file_struct;
+pub mod jsonc;
+
+/// Commented config template. Its values
+/// match `file_struct::ConfigFile::default()`.
+pub const TEMPLATE: &str = include_str!("generate_conf/template.jsonc");
 
 /// Generate config file if it does not exist.
 /// Any `user_path` is valid but blank.
@@ -42,7 +47,7 @@ fn create_file(path: &str) -> Result<File> {
 pub fn validate_path(path: &mut String) {
     if !path.ends_with("deployer-config.jsonc") {
         if !path.ends_with("/") {
-            path.push_str("/");
+            path.push('/');
         }
         path.push_str("deployer-config.jsonc");
     }
@@ -50,16 +55,29 @@ pub fn validate_path(path: &mut String) {
 }
 
 /// This function is for generating default deployer
-/// configuration file structure. Used when deployer
-/// generates configuration file for the first time.
+/// configuration file. Used when deployer generates
+/// configuration file for the first time. Every field
+/// is explained in a comment.
 ///
-/// The function will return an error if either fails
-/// to serialise structure into json or to write the data
-/// into the file.
+/// The function will return an error if it fails
+/// to write the data into the file.
 fn write_config(file: &mut File) -> Result<()> {
-    let config_file = file_struct::ConfigFile::default();
-    let data = serde_json::to_string_pretty(&config_file)?;
-    writeln!(file, "{}", data)?;
+    write!(file, "{}", TEMPLATE)?;
     file.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_struct::ConfigFile;
+
+    #[test]
+    fn test_template_matches_default() {
+        let config: ConfigFile = jsonc::parse(TEMPLATE).unwrap();
+        assert_eq!(
+            serde_json::to_value(config).unwrap(),
+            serde_json::to_value(ConfigFile::default()).unwrap()
+        );
+    }
+}
@@ -1,24 +1,551 @@
 use serde_derive::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Display, net::SocketAddr};
 
 #[derive(Debug, Deserialize)]
 pub struct Commit {
     pub sha: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub name: String,
     pub root_dir: String,
     pub build_dir: String,
+    /// Command the built-in supervisor runs from the
+    /// current release (`build_dir/name`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Zero-downtime deploys through the built-in reverse proxy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<ProxySettings>,
+    /// Environment variables of the supervised process.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Deploy a prebuilt release asset instead of building.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset: Option<AssetSettings>,
+    /// Globs of files outside of `root_dir`, relative to the
+    /// repository, that the service is rebuilt for as well.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watch_paths: Vec<String>,
+    /// `name` before an environment's `unit` replaced it.
+    #[serde(skip)]
+    pub base_name: Option<String>,
+}
+
+impl Service {
+    /// Name as written in `services`, without the environment.
+    pub fn base_name(&self) -> &str {
+        self.base_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Prebuilt asset of the deployed GitHub Release. `.tar.gz`,
+/// `.tgz`, `.tar` and `.zip` are unpacked, anything else is
+/// a single executable. `{tag}` and `{version}` (the tag
+/// without a leading `v`) are replaced in both names.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AssetSettings {
+    pub name: String,
+    /// Asset with SHA-256 checksums, as printed by `sha256sum`.
+    pub checksums: String,
+}
+
+/// Authenticate as a GitHub App installation instead of with a
+/// token. Installation tokens are minted from the private key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitHubAppSettings {
+    pub app_id: u64,
+    pub installation_id: u64,
+    /// PEM file of the private key of the app. Relative paths
+    /// are looked up in systemd's `$CREDENTIALS_DIRECTORY`.
+    pub private_key_file: String,
+    /// Contents of `private_key_file`, read by `resolve_tokens`.
+    #[serde(skip)]
+    pub private_key: String,
+}
+
+/// Blue/green deploy settings of a service. Releases alternate
+/// between the two `ports`, the command gets its port in the
+/// `PORT` environment variable and the proxy forwards
+/// `listen` to whichever release is live.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxySettings {
+    pub listen: String,
+    pub ports: [u16; 2],
+    /// HTTP path that must answer with 2xx before
+    /// the new release gets any traffic.
+    pub health_check: String,
+    /// Seconds to wait for the new release to become healthy.
+    pub health_timeout: u64,
+    /// Seconds to wait for open connections to the
+    /// old release to finish before it is stopped.
+    pub drain_timeout: u64,
+}
+
+/// Settings of the built-in process supervisor.
+/// Used on hosts without systemd.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupervisorSettings {
+    pub log_dir: String,
+    /// Size in bytes after which a log file is rotated.
+    pub max_log_size: u64,
+    /// How many rotated log files to keep.
+    pub max_log_files: usize,
+    /// Seconds to wait after SIGTERM before sending SIGKILL.
+    pub stop_timeout: u64,
+}
+
+/// Embedded receiver of GitHub `push` webhooks.
+/// Polling keeps running as a fallback.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub listen: String,
+    pub path: String,
+    /// Secret used to verify `X-Hub-Signature-256`.
+    pub secret: String,
+}
+
+/// Report deploys to GitHub. Every service deployed gets a
+/// GitHub Deployment in environment `<environment>/<service>`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReportSettings {
+    /// Environment of entries without `environments`,
+    /// `production` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    /// Link to the deploy log shown with the deployment statuses.
+    /// `{environment}`, `{service}` and `{commit}` are replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_url: Option<String>,
+    /// Also set the commit status `deployer/<environment>/<service>`.
+    #[serde(default)]
+    pub commit_status: bool,
+}
+
+/// Only deploy commits signed with a trusted key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SignatureSettings {
+    #[serde(default)]
+    pub source: SignatureSource,
+    /// `ssh-keygen` allowed signers file for SSH signatures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_signers: Option<String>,
+    /// Keyring with the trusted keys for GPG signatures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpg_keyring: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureSource {
+    /// Verify the signature of the cloned commit with
+    /// `allowed_signers` or `gpg_keyring`.
+    #[default]
+    Local,
+    /// Trust GitHub's `verification.verified`.
+    Github,
+}
+
+/// Commits whose messages are read for `[skip deploy]`,
+/// `[deploy: <services>]` and `[clean build]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectiveScope {
+    /// The deployed commit only.
+    #[default]
+    Commit,
+    /// Every commit since the one deployed before.
+    SinceLastDeploy,
+    /// Commit messages are ignored.
+    Off,
+}
+
+/// Deploy the newest matching tag or GitHub Release
+/// instead of the head of the branch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackSettings {
+    pub source: TrackSource,
+    /// Glob the tag name must match, e.g. `v1.*`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Semver range the version must match, e.g. `>=2.0.0, <3`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Deploy pre-releases (`v2.0.0-rc.1`) too.
+    #[serde(default)]
+    pub prereleases: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackSource {
+    Tags,
+    Releases,
+}
+
+impl Display for TrackSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.source {
+            TrackSource::Tags => write!(f, "tags")?,
+            TrackSource::Releases => write!(f, "releases")?,
+        }
+        if let Some(pattern) = &self.pattern {
+            write!(f, " {}", pattern)?;
+        }
+        if let Some(version) = &self.version {
+            write!(f, " {}", version)?;
+        }
+        Ok(())
+    }
+}
+
+/// A branch deployed next to the others,
+/// e.g. `develop` to staging and `main` to production.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvironmentSettings {
+    pub name: String,
+    /// Not used with `track`.
+    #[serde(default)]
+    pub branch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track: Option<TrackSettings>,
+    /// Releases of all services go here instead of their `build_dir`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_root: Option<String>,
+    /// Added to the environment variables of every service.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Name of a service in this environment, `{service}`
+    /// and `{environment}` are replaced. Names processes,
+    /// releases and log files.
+    #[serde(default = "default_unit")]
+    pub unit: String,
+    /// Added to the proxy `listen` port and `ports`,
+    /// so environments don't fight over them.
+    #[serde(default)]
+    pub port_offset: u16,
+}
+
+impl EnvironmentSettings {
+    /// `service` as it is deployed to this environment.
+    fn service(&self, service: &Service) -> Result<Service, String> {
+        let mut service = service.clone();
+        service.base_name = Some(service.name.clone());
+        service.name = self
+            .unit
+            .replace("{service}", &service.name)
+            .replace("{environment}", &self.name);
+        if let Some(root) = &self.build_root {
+            service.build_dir = root.clone();
+        }
+        service.env.extend(self.env.clone());
+        if let Some(proxy) = &mut service.proxy {
+            let mut listen: SocketAddr = proxy
+                .listen
+                .parse()
+                .map_err(|_| format!("invalid proxy address \"{}\"", proxy.listen))?;
+            let offset = |port: u16| {
+                port.checked_add(self.port_offset)
+                    .ok_or_else(|| format!("port_offset: {} is too large", self.port_offset))
+            };
+            listen.set_port(offset(listen.port())?);
+            proxy.listen = listen.to_string();
+            proxy.ports = [offset(proxy.ports[0])?, offset(proxy.ports[1])?];
+        }
+        Ok(service)
+    }
+}
+
+/// Repository to watch and the services built from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepositorySettings {
+    pub repository: String,
+    /// Where the repository is hosted: `github`, `gitlab`,
+    /// `gitea`, `forgejo` or `git` for any git remote.
+    #[serde(default = "default_provider")]
+    pub provider: String,
+    /// URL of a self-hosted instance, `https://` and the
+    /// host of `repository` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// Not used with `environments` or `track`.
+    #[serde(default)]
+    pub branch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track: Option<TrackSettings>,
+    /// Plaintext token. Prefer `token_env` or `token_file`.
+    #[serde(default)]
+    pub token: String,
+    /// Environment variable holding the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+    /// File holding the token. Relative paths are
+    /// looked up in systemd's `$CREDENTIALS_DIRECTORY`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
+    /// Use a GitHub App instead of a token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub github_app: Option<GitHubAppSettings>,
+    /// User to clone as with `provider: "git"`, unless
+    /// the URL names one. `git` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Private key for SSH remotes. Without it, ssh-agent is asked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<String>,
+    pub pull_dir: String,
+    /// Check runs or commit status contexts that must have
+    /// succeeded before a commit is deployed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_checks: Vec<String>,
+    /// Report deploys to the GitHub Deployments API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<ReportSettings>,
+    /// Refuse commits that are not signed by a trusted key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signatures: Option<SignatureSettings>,
+    /// Which commit messages directives are taken from.
+    #[serde(default)]
+    pub directives: DirectiveScope,
+    /// Seconds between checks for new commits.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Up to this many seconds are randomly added to `poll_interval`.
+    #[serde(default = "default_poll_jitter")]
+    pub poll_jitter: u64,
+    pub services: Vec<Service>,
+    /// Deploy several branches, each to its own environment.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub environments: Vec<EnvironmentSettings>,
+    /// Environment this entry was expanded from by `normalise`.
+    #[serde(skip)]
+    pub environment: Option<String>,
+    /// Where this entry is in the config file, see `ConfigFile::field`.
+    #[serde(skip)]
+    pub prefix: String,
 }
 
+impl RepositorySettings {
+    /// Identifies the repository in the running Deployer.
+    pub fn id(&self) -> String {
+        match &self.track {
+            Some(track) => format!("{}@{}", self.repository, track),
+            None => format!("{}@{}", self.repository, self.branch),
+        }
+    }
+}
+
+/// The config file. A single repository is described by the
+/// top-level fields, several by `repositories`. `normalise`
+/// turns the former into the latter, so everything past
+/// loading only looks at `repositories`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConfigFile {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub repository: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub branch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track: Option<TrackSettings>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub github_app: Option<GitHubAppSettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub pull_dir: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_checks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<ReportSettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signatures: Option<SignatureSettings>,
+    #[serde(default)]
+    pub directives: DirectiveScope,
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    #[serde(default = "default_poll_jitter")]
+    pub poll_jitter: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub services: Vec<Service>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub environments: Vec<EnvironmentSettings>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repositories: Vec<RepositorySettings>,
+    /// JSON lines file the deploy history is kept in.
+    /// Without it the history is lost on restart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supervisor: Option<SupervisorSettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookSettings>,
+}
+
+impl ConfigFile {
+    /// Move the single repository format into `repositories`
+    /// and give every environment an entry of its own.
+    ///
+    /// Fails if both formats are used at once.
+    pub fn normalise(&mut self) -> Result<(), String> {
+        let single = self.repositories.is_empty();
+        let top_level = self.top_level_fields();
+        if !single && !top_level.is_empty() {
+            return Err(format!(
+                "repositories: can't be used together with top-level {}",
+                top_level.join(", ")
+            ));
+        }
+        if single {
+            self.repositories.push(RepositorySettings {
+                repository: std::mem::take(&mut self.repository),
+                provider: self.provider.take().unwrap_or_else(default_provider),
+                base_url: self.base_url.take(),
+                branch: std::mem::take(&mut self.branch),
+                track: self.track.take(),
+                token: std::mem::take(&mut self.token),
+                token_env: self.token_env.take(),
+                token_file: self.token_file.take(),
+                github_app: self.github_app.take(),
+                username: self.username.take(),
+                ssh_key: self.ssh_key.take(),
+                pull_dir: std::mem::take(&mut self.pull_dir),
+                required_checks: std::mem::take(&mut self.required_checks),
+                report: self.report.take(),
+                signatures: self.signatures.take(),
+                directives: self.directives,
+                poll_interval: self.poll_interval,
+                poll_jitter: self.poll_jitter,
+                services: std::mem::take(&mut self.services),
+                environments: std::mem::take(&mut self.environments),
+                environment: None,
+                prefix: String::new(),
+            });
+        }
+
+        let mut repositories = Vec::new();
+        for (i, mut repository) in std::mem::take(&mut self.repositories)
+            .into_iter()
+            .enumerate()
+        {
+            let prefix = if single {
+                String::new()
+            } else {
+                format!("repositories[{}].", i)
+            };
+            if repository.environments.is_empty() {
+                repository.prefix = prefix;
+                repositories.push(repository);
+                continue;
+            }
+            let environments = std::mem::take(&mut repository.environments);
+            for (j, environment) in environments.iter().enumerate() {
+                let services = repository
+                    .services
+                    .iter()
+                    .map(|s| environment.service(s))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("{}environments[{}].{}", prefix, j, e))?;
+                repositories.push(RepositorySettings {
+                    branch: environment.branch.clone(),
+                    track: environment.track.clone(),
+                    services,
+                    environment: Some(environment.name.clone()),
+                    prefix: prefix.clone(),
+                    ..repository.clone()
+                });
+            }
+        }
+        self.repositories = repositories;
+        Ok(())
+    }
+
+    /// Top-level repository fields that are set, which
+    /// `repositories` would otherwise silently ignore.
+    fn top_level_fields(&self) -> Vec<&'static str> {
+        [
+            ("repository", !self.repository.is_empty()),
+            ("provider", self.provider.is_some()),
+            ("base_url", self.base_url.is_some()),
+            ("branch", !self.branch.is_empty()),
+            ("track", self.track.is_some()),
+            ("token", !self.token.is_empty()),
+            ("token_env", self.token_env.is_some()),
+            ("token_file", self.token_file.is_some()),
+            ("github_app", self.github_app.is_some()),
+            ("username", self.username.is_some()),
+            ("ssh_key", self.ssh_key.is_some()),
+            ("pull_dir", !self.pull_dir.is_empty()),
+            ("required_checks", !self.required_checks.is_empty()),
+            ("report", self.report.is_some()),
+            ("signatures", self.signatures.is_some()),
+            ("directives", self.directives != DirectiveScope::default()),
+            (
+                "poll_interval",
+                self.poll_interval != default_poll_interval(),
+            ),
+            ("poll_jitter", self.poll_jitter != default_poll_jitter()),
+            ("services", !self.services.is_empty()),
+            ("environments", !self.environments.is_empty()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect()
+    }
+
+    /// Name of `field` of the repository at `index` the way
+    /// it is written in the config file, for error messages.
+    pub fn field(&self, index: usize, field: &str) -> String {
+        let repository = &self.repositories[index];
+        match &repository.environment {
+            Some(environment) => format!("{}{} ({})", repository.prefix, field, environment),
+            None => format!("{}{}", repository.prefix, field),
+        }
+    }
+
+    pub fn repository(&self, id: &str) -> Option<&RepositorySettings> {
+        self.repositories.iter().find(|r| r.id() == id)
+    }
+
+    /// Services of all repositories.
+    pub fn services(&self) -> impl Iterator<Item = &Service> {
+        self.repositories.iter().flat_map(|r| r.services.iter())
+    }
+
+    pub fn service(&self, name: &str) -> Option<&Service> {
+        self.services().find(|s| s.name == name)
+    }
+}
+
+fn default_unit() -> String {
+    "{service}-{environment}".to_owned()
+}
+
+fn default_provider() -> String {
+    "github".to_owned()
+}
+
+fn default_poll_interval() -> u64 {
+    60
+}
+
+fn default_poll_jitter() -> u64 {
+    5
 }
 
 impl Default for Service {
@@ -27,6 +554,12 @@ impl Default for Service {
             name: "service-name".to_owned(),
             root_dir: "/var/www/your_repository/backend/my_service".to_owned(),
             build_dir: "/var/www/my_service".to_owned(),
+            command: None,
+            proxy: None,
+            env: BTreeMap::new(),
+            asset: None,
+            watch_paths: Vec::new(),
+            base_name: None,
         }
     }
 }
@@ -36,9 +569,158 @@ impl Default for ConfigFile {
         ConfigFile {
             branch: "main".to_owned(),
             repository: "github.com/your-repository/link".to_owned(),
+            provider: None,
+            base_url: None,
+            track: None,
+            token: "YOUR-GITHUB-TOKEN-HERE".to_owned(),
+            token_env: None,
+            token_file: None,
+            github_app: None,
+            username: None,
+            ssh_key: None,
+            pull_dir: "/var/www".to_owned(),
+            required_checks: Vec::new(),
+            report: None,
+            signatures: None,
+            directives: DirectiveScope::Commit,
+            poll_interval: default_poll_interval(),
+            poll_jitter: default_poll_jitter(),
+            services: vec![Service::default()],
+            environments: Vec::new(),
+            repositories: Vec::new(),
+            history_file: None,
+            supervisor: None,
+            webhook: None,
+        }
+    }
+}
+
+impl Default for RepositorySettings {
+    fn default() -> Self {
+        RepositorySettings {
+            repository: "github.com/your-repository/link".to_owned(),
+            provider: default_provider(),
+            base_url: None,
+            branch: "main".to_owned(),
+            track: None,
             token: "YOUR-GITHUB-TOKEN-HERE".to_owned(),
+            token_env: None,
+            token_file: None,
+            github_app: None,
+            username: None,
+            ssh_key: None,
             pull_dir: "/var/www".to_owned(),
+            required_checks: Vec::new(),
+            report: None,
+            signatures: None,
+            directives: DirectiveScope::Commit,
+            poll_interval: default_poll_interval(),
+            poll_jitter: default_poll_jitter(),
             services: vec![Service::default()],
+            environments: Vec::new(),
+            environment: None,
+            prefix: String::new(),
+        }
+    }
+}
+
+impl Default for SupervisorSettings {
+    fn default() -> Self {
+        SupervisorSettings {
+            log_dir: "/var/log/deployer".to_owned(),
+            max_log_size: 10 * 1024 * 1024,
+            max_log_files: 5,
+            stop_timeout: 10,
+        }
+    }
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        ProxySettings {
+            listen: "0.0.0.0:8080".to_owned(),
+            ports: [9001, 9002],
+            health_check: "/health".to_owned(),
+            health_timeout: 30,
+            drain_timeout: 30,
+        }
+    }
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        WebhookSettings {
+            listen: "0.0.0.0:9000".to_owned(),
+            path: "/webhook".to_owned(),
+            secret: String::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalise_environments() {
+        let mut config = ConfigFile {
+            services: vec![Service {
+                name: "api".to_owned(),
+                proxy: Some(ProxySettings::default()),
+                env: BTreeMap::from([("LOG".to_owned(), "info".to_owned())]),
+                ..Service::default()
+            }],
+            environments: vec![
+                EnvironmentSettings {
+                    name: "staging".to_owned(),
+                    branch: "develop".to_owned(),
+                    track: None,
+                    build_root: Some("/srv/staging".to_owned()),
+                    env: BTreeMap::from([("LOG".to_owned(), "debug".to_owned())]),
+                    unit: default_unit(),
+                    port_offset: 100,
+                },
+                EnvironmentSettings {
+                    name: "production".to_owned(),
+                    branch: String::new(),
+                    track: Some(TrackSettings {
+                        source: TrackSource::Releases,
+                        pattern: None,
+                        version: Some(">=2.0.0, <3".to_owned()),
+                        prereleases: false,
+                    }),
+                    build_root: None,
+                    env: BTreeMap::new(),
+                    unit: "{service}".to_owned(),
+                    port_offset: 0,
+                },
+            ],
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        assert_eq!(config.repositories.len(), 2);
+
+        let staging = &config.repositories[0];
+        assert_eq!(staging.id(), "github.com/your-repository/link@develop");
+        assert_eq!(staging.environment.as_deref(), Some("staging"));
+        let api = &staging.services[0];
+        assert_eq!(api.name, "api-staging");
+        assert_eq!(api.base_name(), "api");
+        assert_eq!(api.build_dir, "/srv/staging");
+        assert_eq!(api.env["LOG"], "debug");
+        let proxy = api.proxy.as_ref().unwrap();
+        assert_eq!(proxy.listen, "0.0.0.0:8180");
+        assert_eq!(proxy.ports, [9101, 9102]);
+        assert_eq!(config.field(0, "branch"), "branch (staging)");
+
+        let production = &config.repositories[1];
+        assert_eq!(
+            production.id(),
+            "github.com/your-repository/link@releases >=2.0.0, <3"
+        );
+        assert_eq!(production.services[0].name, "api");
+        assert_eq!(production.services[0].base_name(), "api");
+        assert_eq!(production.services[0].build_dir, "/var/www/my_service");
+        assert_eq!(production.services[0].env["LOG"], "info");
+    }
+}
@@ -0,0 +1,179 @@
+// JSON with comments (`//`, `/* */`) and trailing commas.
+// Comments and trailing commas are blanked out with spaces,
+// so line and column of serde_json errors still point
+// into the original file.
+
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    /// Offending line of the original input.
+    pub snippet: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )?;
+        let number = self.line.to_string();
+        writeln!(f, "{} | {}", number, self.snippet)?;
+        write!(
+            f,
+            "{} | {}^",
+            " ".repeat(number.len()),
+            " ".repeat(self.column.saturating_sub(1))
+        )
+    }
+}
+
+/// Parse JSONC into `T`.
+pub fn parse<T: DeserializeOwned>(input: &str) -> Result<T, ParseError> {
+    let json = strip(input);
+    serde_json::from_str(&json).map_err(|e| {
+        // serde_json appends " at line X column Y" itself.
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(index) => message[..index].to_owned(),
+            None => message,
+        };
+        ParseError {
+            line: e.line(),
+            column: e.column(),
+            message,
+            snippet: input
+                .lines()
+                .nth(e.line().saturating_sub(1))
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    })
+}
+
+/// Turn JSONC into plain JSON of the same layout.
+pub fn strip(input: &str) -> String {
+    let mut out = input.as_bytes().to_vec();
+    blank_comments(&mut out);
+    blank_trailing_commas(&mut out);
+    // Only ASCII bytes were replaced with spaces and
+    // multi-byte characters were blanked byte by byte.
+    String::from_utf8(out).unwrap_or_default()
+}
+
+fn blank_comments(bytes: &mut [u8]) {
+    let mut i = 0;
+    let mut in_string = false;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if in_string {
+            match b {
+                b'\\' => i += 1,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            i += 1;
+            continue;
+        }
+        match (b, bytes.get(i + 1)) {
+            (b'"', _) => in_string = true,
+            (b'/', Some(b'/')) => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    bytes[i] = b' ';
+                    i += 1;
+                }
+                continue;
+            }
+            (b'/', Some(b'*')) => {
+                bytes[i] = b' ';
+                bytes[i + 1] = b' ';
+                i += 2;
+                while i < bytes.len() {
+                    if bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/') {
+                        bytes[i] = b' ';
+                        bytes[i + 1] = b' ';
+                        i += 2;
+                        break;
+                    }
+                    if bytes[i] != b'\n' {
+                        bytes[i] = b' ';
+                    }
+                    i += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+/// Must run after `blank_comments`.
+fn blank_trailing_commas(bytes: &mut [u8]) {
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if in_string {
+            match b {
+                b'\\' => i += 1,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else if b == b'"' {
+            in_string = true;
+        } else if b == b',' {
+            let next = bytes[i + 1..]
+                .iter()
+                .find(|c| !c.is_ascii_whitespace());
+            if matches!(next, Some(b'}') | Some(b']')) {
+                bytes[i] = b' ';
+            }
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_comments_and_trailing_commas() {
+        let input = r#"{
+            // line comment
+            "url": "https://github.com", /* block
+            comment */ "list": [1, 2, 3,],
+            "text": "// not a comment, /* neither */",
+        }"#;
+        let value: Value = parse(input).unwrap();
+        assert_eq!(value["url"], "https://github.com");
+        assert_eq!(value["list"], serde_json::json!([1, 2, 3]));
+        assert_eq!(value["text"], "// not a comment, /* neither */");
+    }
+
+    #[test]
+    fn test_escaped_quote() {
+        let value: Value = parse(r#"{ "a": "say \"hi\" // there", }"#).unwrap();
+        assert_eq!(value["a"], "say \"hi\" // there");
+    }
+
+    #[test]
+    fn test_error_position() {
+        let input = "{\n  // comment\n  \"branch\" \"main\"\n}";
+        let e = parse::<Value>(input).unwrap_err();
+        assert_eq!(e.line, 3);
+        assert_eq!(e.column, 12);
+        assert_eq!(e.snippet, "  \"branch\" \"main\"");
+        assert_eq!(
+            e.to_string(),
+            "expected `:` at line 3, column 12\n3 |   \"branch\" \"main\"\n  |            ^"
+        );
+    }
+}
@@ -14,6 +14,12 @@ pub fn help() {
             name: "run <path to config>",
             description: "Start Deployer.",
         },
+        Command {
+            name: "check <path to config>",
+            description: "Check config file and report all problems.
+            \t\t\t\t  Add --online to also check repository,
+            \t\t\t\t  branch and token against GitHub.",
+        },
         Command {
             name: "start <service>",
             description: "Starts a service.",
@@ -26,10 +32,26 @@ pub fn help() {
             name: "restart <service>",
             description: "Restarts a service.",
         },
+        Command {
+            name: "deploy",
+            description: "\t\tDeploy the latest commit right now.",
+        },
+        Command {
+            name: "pause",
+            description: "\t\tPause polling for new commits.",
+        },
+        Command {
+            name: "resume",
+            description: "\t\tResume polling for new commits.",
+        },
         Command {
             name: "reload",
             description: "\t\tReload config file to apply new configuration.",
         },
+        Command {
+            name: "history [count]",
+            description: "\tShow the latest deploys (20 by default).",
+        },
         // Sort of dashboard where you'd see all services and their statuses
         // so you don't get lost which service is working and which is not.
         // Useful, especially for microservices.
@@ -48,6 +70,6 @@ pub fn help() {
     println!("Available commands:");
     for c in commands {
         print!("\tdeployer {}", c.name);
-        print!("\t{}\n", c.description);
+        println!("\t{}", c.description);
     }
 }
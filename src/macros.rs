@@ -28,6 +28,6 @@ macro_rules! arg_len {
 macro_rules! log {
     ($($arg:tt)*) => {
         let time: DateTime<Local> = Local::now();
-        println!("[{}] {}", time, format_args!($($arg)*));
+        println!("[{}] {}", time, $crate::secrets::redact(&format!($($arg)*)));
     }
 }
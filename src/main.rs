@@ -1,9 +1,14 @@
-use std::{env, io::ErrorKind};
+use error::DeployerError;
+use run_deployer::control::{self, Request, Response};
+use std::{env, io::ErrorKind, process};
 
+mod check_conf;
+mod error;
 mod generate_conf;
 mod help;
 mod macros;
 mod run_deployer;
+mod secrets;
 
 #[tokio::main]
 async fn main() {
@@ -12,26 +17,120 @@ async fn main() {
 
     match args[1].as_str() {
         "--help" => help::help(),
-        "config" => handle_generate(&args),
-        "run" => handle_run(&args).await,
+        "config" => exit_on_error(handle_generate(&args)),
+        "run" => exit_on_error(handle_run(&args).await),
+        "check" => exit_on_error(handle_check(&args).await),
+        "services" => handle_services(&args).await,
+        "deploy" => handle_control(Request::Deploy).await,
+        "pause" => handle_control(Request::Pause).await,
+        "resume" => handle_control(Request::Resume).await,
+        "reload" => handle_control(Request::Reload).await,
+        "history" => handle_history(&args).await,
+        "start" | "stop" | "restart" => handle_service(&args).await,
         _ => println!("{}", macros::HELP_MSG),
     }
 }
 
-fn handle_generate(args: &[String]) {
-    arg_len!(args.len(), 3, macros::HELP_MSG);
-    match generate_conf::generate(&args[2]) {
-        Ok(()) => println!("Created successfully."),
+/// Print the error and exit with its exit code.
+fn exit_on_error(result: Result<(), DeployerError>) {
+    if let Err(e) = result {
+        eprintln!("Error: {}", secrets::redact(&e.to_string()));
+        process::exit(e.exit_code());
+    }
+}
+
+fn handle_generate(args: &[String]) -> Result<(), DeployerError> {
+    if args.len() < 3 {
+        println!("{}", macros::HELP_MSG);
+        return Ok(());
+    }
+    let path = args[2].clone();
+    match generate_conf::generate(&path) {
+        Ok(()) => {
+            println!("Created successfully.");
+            Ok(())
+        }
         Err(e) => match e.kind() {
-            ErrorKind::AlreadyExists => panic!("The configuration file already exists."),
-            _ => panic!("An error occurred while generating config file: {e}"),
+            ErrorKind::AlreadyExists => Err(DeployerError::Config {
+                path,
+                message: "The configuration file already exists.".to_owned(),
+            }),
+            _ => Err(DeployerError::Io { path, source: e }),
         },
     }
 }
 
-async fn handle_run(args: &[String]) {
-    arg_len!(args.len(), 3, macros::HELP_MSG);
+async fn handle_run(args: &[String]) -> Result<(), DeployerError> {
+    if args.len() < 3 {
+        println!("{}", macros::HELP_MSG);
+        return Ok(());
+    }
     let mut path = String::from(&args[2]);
     generate_conf::validate_path(&mut path);
-    run_deployer::run(&path).await;
+    run_deployer::run(&path).await
+}
+
+async fn handle_check(args: &[String]) -> Result<(), DeployerError> {
+    if args.len() < 3 {
+        println!("{}", macros::HELP_MSG);
+        return Ok(());
+    }
+    let mut path = String::from(&args[2]);
+    generate_conf::validate_path(&mut path);
+    let online = args[3..].iter().any(|a| a == "--online");
+    check_conf::check(&path, online).await
+}
+
+async fn handle_services(args: &[String]) {
+    arg_len!(args.len(), 3, macros::HELP_MSG);
+    match args[2].as_str() {
+        "status" => handle_control(Request::Status).await,
+        _ => println!("{}", macros::HELP_MSG),
+    }
+}
+
+async fn handle_service(args: &[String]) {
+    arg_len!(args.len(), 3, macros::HELP_MSG);
+    let service = args[2].clone();
+    let request = match args[1].as_str() {
+        "start" => Request::Start { service },
+        "stop" => Request::Stop { service },
+        _ => Request::Restart { service },
+    };
+    handle_control(request).await;
+}
+
+/// Print the latest deploys, 20 unless a limit is given.
+async fn handle_history(args: &[String]) {
+    let limit = match args.get(2).map(|n| n.parse()) {
+        None => 20,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            println!("{}", macros::HELP_MSG);
+            return;
+        }
+    };
+    handle_control(Request::History { limit }).await
+}
+
+/// Send a request to the running Deployer
+/// and print its response.
+async fn handle_control(request: Request) {
+    match control::send(&control::socket_path(), &request).await {
+        Ok(Response::Ok { message }) => println!("{}", message),
+        Ok(Response::Status(status)) => print!("{}", status),
+        Ok(Response::History { deploys }) => {
+            for deploy in deploys {
+                println!("{}", deploy);
+            }
+        }
+        Ok(Response::Error { message }) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
 }
@@ -1,45 +1,189 @@
-use crate::generate_conf::file_struct::ConfigFile;
-use serde_json;
-use std::{fs::File, io::Read, path::Path};
+use crate::check_conf::{self, Level};
+use crate::error::DeployerError;
+use crate::generate_conf::{
+    file_struct::ConfigFile,
+    jsonc,
+};
+use crate::log;
+use crate::secrets;
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::{collections::HashSet, fs::File, io::Read, sync::Arc};
+use tokio::task::JoinSet;
 
+pub mod control;
+pub mod history;
+pub mod provider;
+pub mod proxy;
 pub mod pull;
+#[cfg(test)]
+pub mod stand_in;
+pub mod supervisor;
+pub mod webhook;
 
-use pull::{ping, RepositoryInfo};
+use control::Control;
+use pull::ping;
 
-/// Function that starts Deployer. It makes
-/// request to GitHub's REST API every 60 seconds.
+/// Function that starts Deployer. It makes request to
+/// GitHub's REST API every `poll_interval` seconds for
+/// every repository.
 /// As an argument it takes path to the config file.
 ///
-/// Panics if directory does not exist, no services
-/// specified, token/repository/branch is not specified or
-/// repository link is invalid.
-pub async fn run(path: &str) {
-    let config = deserialise(path);
-    if config.token.is_empty() || config.token == "YOUR-GITHUB-TOKEN-HERE" {
-        panic!("Github token is not specified!");
+/// Also listens on the control socket so CLI
+/// subcommands can talk to the running Deployer.
+///
+/// Fails if directory does not exist, no services
+/// specified, token/repository/branch is not specified,
+/// repository link is invalid or control socket is taken.
+pub async fn run(path: &str) -> Result<(), DeployerError> {
+    let config = load(path)?;
+
+    let socket = control::socket_path();
+    let listener = control::bind(&socket).map_err(|source| DeployerError::Io {
+        path: socket.clone(),
+        source,
+    })?;
+    log!("Listening for commands on {}", socket);
+
+    let webhook = match &config.webhook {
+        Some(settings) => {
+            let listener = tokio::net::TcpListener::bind(&settings.listen)
+                .await
+                .map_err(|source| DeployerError::Io {
+                    path: settings.listen.clone(),
+                    source,
+                })?;
+            log!(
+                "Listening for webhooks on {}{}",
+                settings.listen,
+                settings.path
+            );
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let control = Arc::new(Control::new(path, config));
+    tokio::spawn(control::serve(listener, control.clone()));
+    if let Some(listener) = webhook {
+        tokio::spawn(webhook::serve(listener, control.clone()));
     }
-    if config.repository.is_empty() || config.repository == "github.com/your-repository/link" {
-        panic!("Github repository is not specified!");
+    start_services(&control);
+
+    let result = watch(&control).await;
+    control.supervisor.stop_all().await;
+    let _ = std::fs::remove_file(&socket);
+    result
+}
+
+/// Poll every repository in its own task until Ctrl+C.
+/// Tasks of repositories added by `reload` are started,
+/// removed repositories stop by themselves.
+///
+/// A repository that fails, for instance on bad credentials,
+/// is dropped and tried again after the next `reload`. One
+/// whose polling panicked is restarted.
+async fn watch(control: &Arc<Control>) -> Result<(), DeployerError> {
+    let mut tasks = JoinSet::new();
+    let mut watched = HashSet::new();
+    let mut failed = HashSet::new();
+    loop {
+        for repository in &control.config().repositories {
+            let id = repository.id();
+            if watched.insert(id.clone()) {
+                log!("Watching {}", id);
+                let control = control.clone();
+                tasks.spawn(async move {
+                    // Polled in a task of its own, so a panic
+                    // still tells which repository it was.
+                    let polling = {
+                        let id = id.clone();
+                        tokio::spawn(async move { ping(&control, &id).await })
+                    };
+                    (id, polling.await)
+                });
+            }
+        }
+
+        tokio::select! {
+            _ = control.reloaded() => {
+                for id in failed.drain() {
+                    watched.remove(&id);
+                }
+            }
+            Some(Ok((id, result))) = tasks.join_next(), if !tasks.is_empty() => match result {
+                Ok(Ok(())) => {
+                    watched.remove(&id);
+                }
+                Ok(Err(e)) => {
+                    log!("Stopped watching {}: {}", id, e);
+                    control.record_poll(&id, None, Some(e.to_string()));
+                    failed.insert(id);
+                }
+                Err(e) => {
+                    log!("Polling {} failed: {}, restarting", id, e);
+                    watched.remove(&id);
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                log!("Shutting down");
+                return Ok(());
+            }
+        }
     }
-    let repository = url_fmt(&config.repository, &config.branch);
+}
 
-    // NOTE: Only global directories are valid yet
-    validate_dir(&config.pull_dir);
-    if config.services.len() == 0 {
-        panic!("Not a single service specified :<");
+/// Start services that already have a release
+/// if the built-in supervisor is enabled.
+/// Proxies of blue/green services are started as well.
+fn start_services(control: &Control) {
+    let config = control.config();
+    for service in config.services() {
+        if let Err(e) = control.proxy(service) {
+            log!("{}", e);
+        }
+    }
+    if config.supervisor.is_none() {
+        return;
     }
+    for service in config.services().filter(|s| s.command.is_some()) {
+        if let Err(e) = control.start_service(&service.name) {
+            log!("{}", e);
+        }
+    }
+}
 
-    ping(&config, &repository).await.unwrap();
+/// Read and check the config file. Used on start
+/// and by `reload`, where a broken config must not
+/// take down the running Deployer.
+///
+/// Fails with every error `deployer check` would report.
+pub fn load(path: &str) -> Result<ConfigFile, DeployerError> {
+    let config = deserialise(path)?;
+    let problems = check_conf::diagnose(&config);
+    for warning in problems.iter().filter(|p| p.level == Level::Warning) {
+        log!("{}", warning);
+    }
+    let errors: Vec<String> = problems
+        .iter()
+        .filter(|p| p.level == Level::Error)
+        .map(|p| p.message.clone())
+        .collect();
+    if !errors.is_empty() {
+        return Err(DeployerError::Config {
+            path: path.to_owned(),
+            message: errors.join("; "),
+        });
+    }
+    Ok(config)
 }
 
-/// Formats URL from `github.com/author/their-repo` to
-/// `https://api.github.com/repos/author/their-repo/commits`.
-/// Panics if URL is badly formatted.
-fn url_fmt<'a>(url: &'a str, branch: &'a str) -> RepositoryInfo<'a> {
+/// Split `github.com/author/their-repo` into author and repository name.
+pub fn parse_url(url: &str) -> Result<(&str, &str), String> {
     const INVALID_URL: &str = "Invalid repository URL!";
     let list: Vec<&str> = url.split('/').collect();
     if list.len() != 3 {
-        panic!("{}", INVALID_URL);
+        return Err(INVALID_URL.to_owned());
     }
 
     let domain = list[0];
@@ -47,89 +191,107 @@ fn url_fmt<'a>(url: &'a str, branch: &'a str) -> RepositoryInfo<'a> {
     let repository = list[2];
 
     if domain != "github.com" {
-        panic!("Invalid repository domain!");
+        return Err("Invalid repository domain!".to_owned());
     }
 
     if author.is_empty() || repository.is_empty() {
-        panic!("{}", INVALID_URL);
-    }
-
-    if branch.is_empty() {
-        panic!("No main branch specified!");
-    }
-
-    let url = format!(
-        "https://api.github.com/repos/{}/{}/commits/{}",
-        author, repository, branch
-    );
-
-    RepositoryInfo {
-        url,
-        author,
-        name: repository,
+        return Err(INVALID_URL.to_owned());
     }
+    Ok((author, repository))
 }
 
-/// Check if specified directory exists.
-/// Panics if it does not.
-fn validate_dir(dir: &str) {
-    let path = Path::new(dir);
-    if !path.exists() {
-        panic!("Path \"{}\" does not exist!", dir);
-    }
-}
+/// Converts JSONC data from the config file into
+/// normalised `ConfigFile` struct, expanding `${ENV_VAR}`s
+/// and reading the tokens. Fails if the config file
+/// can't be read or isn't valid.
+fn deserialise(path: &str) -> Result<ConfigFile, DeployerError> {
+    let mut buf = String::new();
+    let io_error = |source| DeployerError::Io {
+        path: path.to_owned(),
+        source,
+    };
+    let config_error = |message| DeployerError::Config {
+        path: path.to_owned(),
+        message,
+    };
+    let mut file = File::open(path).map_err(io_error)?;
+    file.read_to_string(&mut buf).map_err(io_error)?;
 
-/// Converts JSON data from the config file into
-/// `ConfigFile` struct. Panics if fails to
-/// either open the config file or to read it.
-fn deserialise(path: &str) -> ConfigFile {
-    let mut buf = Vec::new();
-    let mut file = File::open(path).expect("Failed to open the config file");
-    file.read_to_end(&mut buf)
-        .expect("Failed to read the config file");
-
-    serde_json::from_slice(&buf).expect("Failed to parse json config")
+    let mut value: Value = jsonc::parse(&buf)
+        .map_err(|e| config_error(format!("Failed to parse json config: {e}")))?;
+    secrets::interpolate(&mut value).map_err(|errors| config_error(errors.join("; ")))?;
+    let mut config: ConfigFile = serde_path_to_error::deserialize(value)
+        .map_err(|e| config_error(format!("{}: {}", e.path(), e.inner())))?;
+    config.normalise().map_err(config_error)?;
+    secrets::resolve_tokens(&mut config).map_err(config_error)?;
+    Ok(config)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::generate_conf::file_struct::RepositorySettings;
 
-    // Test URL formatter
     #[test]
-    fn test_valid_url_fmt() {
-        let url = "github.com/Makefolder/deployer";
-        let branch = "master";
-        let repository_info = url_fmt(url, branch);
-        assert_eq!(
-            repository_info.url,
-            "https://api.github.com/repos/Makefolder/deployer/commits/master"
-        );
-        assert_eq!(repository_info.author, "Makefolder");
-        assert_eq!(repository_info.name, "deployer");
+    fn test_load_missing_config() {
+        let result = load("/nonexistent/deployer-config.jsonc");
+        assert_eq!(result.err().unwrap().exit_code(), 7);
     }
 
-    #[test]
-    #[should_panic(expected = "Invalid repository domain!")]
-    fn test_invalid_domain_url_fmt() {
-        let url = "gitlab.com/Makefolder/deployer";
-        let branch = "master";
-        url_fmt(url, branch);
-    }
+    #[tokio::test]
+    async fn test_failing_repository_leaves_others_watched() {
+        let github = stand_in::StandIn::start(vec![(
+            "/api/v3/repos/a/b/commits/main",
+            200,
+            br#"{ "sha": "" }"#.to_vec(),
+        )])
+        .await;
+        let repository = |repository: &str, base_url: Option<String>| RepositorySettings {
+            repository: repository.to_owned(),
+            base_url,
+            token: "token".to_owned(),
+            ..RepositorySettings::default()
+        };
+        let mut config = ConfigFile {
+            repository: String::new(),
+            branch: String::new(),
+            token: String::new(),
+            pull_dir: String::new(),
+            services: Vec::new(),
+            repositories: vec![
+                repository("ghe.corp/a/b", Some(github.url.clone())),
+                // Not on github.com and no base_url.
+                repository("ghe.corp/a/c", None),
+            ],
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        let good = config.repositories[0].id();
+        let control = Arc::new(Control::new("", config));
+        let watching = tokio::spawn({
+            let control = control.clone();
+            async move { watch(&control).await }
+        });
 
-    #[test]
-    #[should_panic(expected = "Invalid repository URL!")]
-    fn test_invalid_author_url_fmt() {
-        let url = "github.com//deployer";
-        let branch = "master";
-        url_fmt(url, branch);
-    }
-
-    #[test]
-    #[should_panic(expected = "Invalid repository URL!")]
-    fn test_invalid_name_url_fmt() {
-        let url = "github.com/Makefolder/";
-        let branch = "master";
-        url_fmt(url, branch);
+        let last_error = || control.status().repositories[1].last_error.clone();
+        let polls = || github.requests().len();
+        for _ in 0..100 {
+            if last_error().is_some() && polls() > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(last_error().unwrap().contains("Invalid repository"));
+        let before = polls();
+        control.poll_now(&good);
+        for _ in 0..100 {
+            if polls() > before {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(polls() > before);
+        assert!(!watching.is_finished());
+        watching.abort();
     }
 }
@@ -0,0 +1,695 @@
+// Control socket of the running Deployer. CLI subcommands such as
+// `deployer services status` or `deployer reload` connect to it and
+// exchange a single line of JSON each way.
+
+use super::{
+    history::{Deploy, DeployResult, History},
+    proxy::Proxy,
+    pull::build::{live_port, release_dir, slot_name},
+    supervisor::Supervisor,
+};
+use crate::generate_conf::file_struct::{ConfigFile, RepositorySettings, Service};
+use crate::log;
+use crate::secrets;
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt::Display,
+    fs::{self, DirBuilder, Permissions},
+    io::{Error as IoError, ErrorKind},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{Mutex as AsyncMutex, MutexGuard, Notify},
+    time::{self, Duration},
+};
+
+pub const DEFAULT_SOCKET: &str = "/run/deployer/deployer.sock";
+
+/// Path to the control socket. Can be overridden with the
+/// `DEPLOYER_SOCKET` environment variable, otherwise it is in
+/// systemd's `$RUNTIME_DIRECTORY` if set.
+pub fn socket_path() -> String {
+    env::var("DEPLOYER_SOCKET")
+        .or_else(|_| env::var("RUNTIME_DIRECTORY").map(|dir| format!("{dir}/deployer.sock")))
+        .unwrap_or_else(|_| DEFAULT_SOCKET.to_owned())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Deploy,
+    Pause,
+    Resume,
+    Reload,
+    Start { service: String },
+    Stop { service: String },
+    Restart { service: String },
+    History { limit: usize },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok { message: String },
+    Status(Status),
+    History { deploys: Vec<Deploy> },
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub paused: bool,
+    pub repositories: Vec<RepositoryStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepositoryStatus {
+    pub repository: String,
+    pub branch: String,
+    pub environment: Option<String>,
+    pub last_commit: Option<String>,
+    pub last_poll: Option<String>,
+    pub last_error: Option<String>,
+    pub services: Vec<ServiceStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub state: String,
+    pub commit: Option<String>,
+    pub deployed_at: Option<String>,
+    /// State of the supervised process, if any.
+    pub process: Option<String>,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Polling:    {}",
+            if self.paused { "paused" } else { "active" }
+        )?;
+        for repository in &self.repositories {
+            write!(f, "\n{}", repository)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for RepositoryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Repository: {} ({})", self.repository, self.branch)?;
+        match &self.environment {
+            Some(environment) => writeln!(f, " -> {}", environment)?,
+            None => writeln!(f)?,
+        }
+        writeln!(
+            f,
+            "Commit:     {}",
+            self.last_commit.as_deref().unwrap_or("-")
+        )?;
+        writeln!(
+            f,
+            "Last poll:  {}",
+            self.last_poll.as_deref().unwrap_or("-")
+        )?;
+        if let Some(e) = &self.last_error {
+            writeln!(f, "Last error: {}", e)?;
+        }
+        writeln!(f, "Services:")?;
+        for s in &self.services {
+            writeln!(
+                f,
+                "\t{}\t{}\t{}\t{}\t{}",
+                s.name,
+                s.state,
+                s.commit.as_deref().unwrap_or("-"),
+                s.deployed_at.as_deref().unwrap_or("-"),
+                s.process.as_deref().unwrap_or("-")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Polling state of a single repository.
+#[derive(Default)]
+struct RepositoryState {
+    force: bool,
+    last_commit: Option<String>,
+    last_poll: Option<DateTime<Local>>,
+    last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    paused: bool,
+    /// By repository ID.
+    repositories: HashMap<String, RepositoryState>,
+}
+
+/// State of the running Deployer shared between
+/// the polling loops and the control socket.
+pub struct Control {
+    path: String,
+    config: RwLock<Arc<ConfigFile>>,
+    state: Mutex<State>,
+    /// Wakes the polling loop of a repository, by repository ID.
+    wake: Mutex<HashMap<String, Arc<Notify>>>,
+    reloaded: Notify,
+    /// Builds of all repositories run one at a time.
+    builds: AsyncMutex<()>,
+    history: History,
+    pub supervisor: Supervisor,
+    proxies: Mutex<HashMap<String, Arc<Proxy>>>,
+}
+
+impl Control {
+    pub fn new(path: &str, config: ConfigFile) -> Self {
+        Control {
+            history: History::open(config.history_file.as_deref()),
+            path: path.to_owned(),
+            config: RwLock::new(Arc::new(config)),
+            state: Mutex::new(State::default()),
+            wake: Mutex::new(HashMap::new()),
+            reloaded: Notify::new(),
+            builds: AsyncMutex::new(()),
+            supervisor: Supervisor::default(),
+            proxies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Path to the config file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Currently active configuration. Replaced on `reload`.
+    pub fn config(&self) -> Arc<ConfigFile> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Returns `true` once after a deploy was requested
+    /// through the socket.
+    pub fn take_force(&self, id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.repositories.get_mut(id) {
+            Some(repository) => std::mem::take(&mut repository.force),
+            None => false,
+        }
+    }
+
+    fn notify(&self, id: &str) -> Arc<Notify> {
+        let mut wake = self.wake.lock().unwrap();
+        wake.entry(id.to_owned()).or_default().clone()
+    }
+
+    /// Sleep for `duration` or until the polling loop of
+    /// repository `id` is woken up by a deploy request.
+    pub async fn wait(&self, id: &str, duration: Duration) {
+        let notify = self.notify(id);
+        tokio::select! {
+            _ = time::sleep(duration) => {}
+            _ = notify.notified() => {}
+        }
+    }
+
+    /// Wake the polling loop of repository `id` without
+    /// forcing a deploy. Used by webhooks: the loop deploys
+    /// only if the branch actually has a new commit.
+    pub fn poll_now(&self, id: &str) {
+        self.notify(id).notify_one();
+    }
+
+    fn wake_all(&self) {
+        for notify in self.wake.lock().unwrap().values() {
+            notify.notify_one();
+        }
+    }
+
+    /// Resolves after the configuration was reloaded.
+    pub async fn reloaded(&self) {
+        self.reloaded.notified().await
+    }
+
+    /// Wait for the turn to build. Held while building.
+    pub async fn build_turn(&self) -> MutexGuard<'_, ()> {
+        self.builds.lock().await
+    }
+
+    pub fn record_poll(&self, id: &str, commit: Option<&str>, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        let repository = state.repositories.entry(id.to_owned()).or_default();
+        repository.last_poll = Some(Local::now());
+        if let Some(sha) = commit {
+            repository.last_commit = Some(sha.to_owned());
+        }
+        repository.last_error = error.map(|e| secrets::redact(&e));
+    }
+
+    pub fn record_deploy(
+        &self,
+        repository: &RepositorySettings,
+        service: &str,
+        commit: &str,
+        result: DeployResult,
+    ) {
+        self.history.record(Deploy {
+            repository: repository.repository.clone(),
+            branch: repository.branch.clone(),
+            environment: repository.environment.clone(),
+            service: service.to_owned(),
+            commit: commit.to_owned(),
+            time: Local::now().to_rfc3339(),
+            result,
+        });
+    }
+
+    /// Latest deploy of `service`.
+    pub fn last_deploy(&self, service: &str) -> Option<Deploy> {
+        self.history.last(service)
+    }
+
+    /// Latest successful deploy of any service of `repository`.
+    pub fn last_deployed(&self, repository: &RepositorySettings) -> Option<Deploy> {
+        self.history.find(|d| {
+            d.result == DeployResult::Deployed
+                && d.repository == repository.repository
+                && d.branch == repository.branch
+                && d.environment == repository.environment
+        })
+    }
+
+    /// What `deployer services status` shows.
+    pub fn status(&self) -> Status {
+        let config = self.config();
+        let state = self.state.lock().unwrap();
+        let repositories = config
+            .repositories
+            .iter()
+            .map(|r| {
+                let services = r
+                    .services
+                    .iter()
+                    .map(|s| match self.history.last(&s.name) {
+                        Some(deploy) => ServiceStatus {
+                            name: s.name.clone(),
+                            state: deploy.result.to_string(),
+                            commit: Some(deploy.commit),
+                            deployed_at: Some(deploy.time),
+                            process: self.process_state(s),
+                        },
+                        None => ServiceStatus {
+                            name: s.name.clone(),
+                            state: "pending".to_owned(),
+                            commit: None,
+                            deployed_at: None,
+                            process: self.process_state(s),
+                        },
+                    })
+                    .collect();
+                let polling = state.repositories.get(&r.id());
+                RepositoryStatus {
+                    repository: r.repository.clone(),
+                    branch: r.branch.clone(),
+                    environment: r.environment.clone(),
+                    last_commit: polling.and_then(|p| p.last_commit.clone()),
+                    last_poll: polling.and_then(|p| p.last_poll.map(|t| t.to_rfc3339())),
+                    last_error: polling.and_then(|p| p.last_error.clone()),
+                    services,
+                }
+            })
+            .collect();
+
+        Status {
+            paused: state.paused,
+            repositories,
+        }
+    }
+
+    /// Proxy of a blue/green service. Bound on first use
+    /// and forwards to the live release, if there is one.
+    pub fn proxy(&self, service: &Service) -> Result<Option<Arc<Proxy>>, String> {
+        let settings = match &service.proxy {
+            Some(settings) => settings,
+            None => return Ok(None),
+        };
+        let mut proxies = self.proxies.lock().unwrap();
+        if let Some(proxy) = proxies.get(&service.name) {
+            return Ok(Some(proxy.clone()));
+        }
+        let live = live_port(Path::new(&service.build_dir), &service.name);
+        let proxy = Proxy::bind(&settings.listen, live).map_err(|e| {
+            format!(
+                "Failed to start proxy of {} on {}: {e}",
+                service.name, settings.listen
+            )
+        })?;
+        log!(
+            "Proxy of {} listens on {}",
+            service.name,
+            proxy.local_addr()
+        );
+        proxies.insert(service.name.clone(), proxy.clone());
+        Ok(Some(proxy))
+    }
+
+    /// Start a service with the built-in supervisor
+    /// from its current release.
+    pub fn start_service(&self, name: &str) -> Result<(), String> {
+        let config = self.config();
+        let settings = config
+            .supervisor
+            .as_ref()
+            .ok_or("Supervisor is not enabled in the config file")?;
+        let service = config
+            .service(name)
+            .ok_or(format!("Unknown service: {name}"))?;
+        if service.command.is_none() {
+            return Err(format!("Service {name} has no command to run"));
+        }
+        let build_dir = Path::new(&service.build_dir);
+        match self.proxy(service)? {
+            None => {
+                let dir = release_dir(build_dir, &service.name);
+                self.supervisor
+                    .start(name, service, &dir, settings, process_env(service, None))
+            }
+            Some(proxy) => {
+                let port = proxy
+                    .active()
+                    .ok_or(format!("Service {name} has no release yet"))?;
+                let slot = slot_name(name, port);
+                let dir = release_dir(build_dir, &slot);
+                self.supervisor.start(
+                    &slot,
+                    service,
+                    &dir,
+                    settings,
+                    process_env(service, Some(port)),
+                )
+            }
+        }
+    }
+
+    /// Stop all processes of a service. Returns `false`
+    /// if none were running.
+    pub async fn stop_service(&self, service: &Service) -> bool {
+        let mut stopped = self.supervisor.stop(&service.name).await;
+        if let Some(settings) = &service.proxy {
+            for port in settings.ports {
+                stopped |= self.supervisor.stop(&slot_name(&service.name, port)).await;
+            }
+        }
+        stopped
+    }
+
+    fn process_state(&self, service: &Service) -> Option<String> {
+        let live = self
+            .proxies
+            .lock()
+            .unwrap()
+            .get(&service.name)
+            .and_then(|p| p.active());
+        match live {
+            Some(port) => self.supervisor.state(&slot_name(&service.name, port)),
+            None => self.supervisor.state(&service.name),
+        }
+    }
+
+    async fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Status => Response::Status(self.status()),
+            Request::History { limit } => Response::History {
+                deploys: self.history.latest(limit),
+            },
+            Request::Deploy => {
+                let config = self.config();
+                let mut state = self.state.lock().unwrap();
+                for repository in &config.repositories {
+                    state.repositories.entry(repository.id()).or_default().force = true;
+                }
+                drop(state);
+                self.wake_all();
+                ok("Deploy triggered.")
+            }
+            Request::Pause => {
+                self.state.lock().unwrap().paused = true;
+                ok("Polling paused.")
+            }
+            Request::Resume => {
+                self.state.lock().unwrap().paused = false;
+                self.wake_all();
+                ok("Polling resumed.")
+            }
+            Request::Reload => match super::load(&self.path) {
+                Ok(config) => {
+                    *self.config.write().unwrap() = Arc::new(config);
+                    self.reloaded.notify_one();
+                    log!("Configuration reloaded from {}", self.path);
+                    ok("Configuration reloaded.")
+                }
+                Err(e) => Response::Error {
+                    message: format!("Failed to reload configuration: {e}"),
+                },
+            },
+            Request::Start { service } => match self.start_service(&service) {
+                Ok(()) => ok(&format!("Started {service}.")),
+                Err(message) => Response::Error { message },
+            },
+            Request::Stop { service } => {
+                let config = self.config();
+                let stopped = match config.service(&service) {
+                    Some(s) => self.stop_service(s).await,
+                    None => false,
+                };
+                if stopped {
+                    ok(&format!("Stopped {service}."))
+                } else {
+                    Response::Error {
+                        message: format!("Service {service} is not running"),
+                    }
+                }
+            }
+            Request::Restart { service } => {
+                let config = self.config();
+                if let Some(s) = config.service(&service) {
+                    self.stop_service(s).await;
+                }
+                match self.start_service(&service) {
+                    Ok(()) => ok(&format!("Restarted {service}.")),
+                    Err(message) => Response::Error { message },
+                }
+            }
+        }
+    }
+}
+
+/// Environment of a supervised process. Blue/green
+/// releases get their `port` in `PORT`.
+pub fn process_env(service: &Service, port: Option<u16>) -> Vec<(String, String)> {
+    let mut env: Vec<_> = service
+        .env
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    if let Some(port) = port {
+        env.push(("PORT".to_owned(), port.to_string()));
+    }
+    env
+}
+
+fn ok(message: &str) -> Response {
+    Response::Ok {
+        message: message.to_owned(),
+    }
+}
+
+/// Bind the control socket and make it accessible
+/// to its owner only. A missing directory is created
+/// accessible to its owner only as well.
+///
+/// Fails if another Deployer is already listening on `path`
+/// or if something else than a socket is there. A stale
+/// socket left after a crash is removed.
+pub fn bind(path: &str) -> Result<UnixListener, IoError> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent().filter(|dir| !dir.exists()) {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(IoError::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(IoError::new(
+                ErrorKind::AddrInUse,
+                format!("Deployer is already running on {}", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    // Bound in a private directory and moved into place once only
+    // its owner can connect, `bind` itself goes by the umask.
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let private = path.with_file_name(format!(".{}.{}", file_name, std::process::id()));
+    let _ = fs::remove_dir_all(&private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    listener
+}
+
+/// Accept connections on the control socket forever.
+pub async fn serve(listener: UnixListener, control: Arc<Control>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let control = control.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &control).await {
+                        log!("Control connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                log!("Failed to accept control connection: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: UnixStream, control: &Control) -> Result<(), Box<dyn Error>> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => control.handle(request).await,
+        Err(e) => Response::Error {
+            message: format!("Invalid request: {e}"),
+        },
+    };
+    let mut data = serde_json::to_vec(&response)?;
+    data.push(b'\n');
+    write.write_all(&data).await?;
+    Ok(())
+}
+
+/// Client side. Send a single request to the running Deployer.
+pub async fn send(path: &str, request: &Request) -> Result<Response, Box<dyn Error>> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| format!("Failed to connect to Deployer at {path} ({e}). Is it running?"))?;
+    let (read, mut write) = stream.into_split();
+
+    let mut data = serde_json::to_vec(request)?;
+    data.push(b'\n');
+    write.write_all(&data).await?;
+
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_format() {
+        let data = serde_json::to_string(&Request::Reload).unwrap();
+        assert_eq!(data, r#"{"command":"reload"}"#);
+    }
+
+    fn config() -> ConfigFile {
+        let mut config = ConfigFile::default();
+        config.normalise().unwrap();
+        config
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_deploy() {
+        let config = config();
+        let id = config.repositories[0].id();
+        let control = Control::new("", config);
+        control.handle(Request::Pause).await;
+        assert!(control.is_paused());
+        control.handle(Request::Resume).await;
+        assert!(!control.is_paused());
+
+        control.handle(Request::Deploy).await;
+        assert!(control.take_force(&id));
+        assert!(!control.take_force(&id));
+    }
+
+    #[test]
+    fn test_last_deployed() {
+        let config = config();
+        let settings = config.repositories[0].clone();
+        let control = Control::new("", config);
+        assert_eq!(control.last_deployed(&settings), None);
+        control.record_deploy(&settings, "api", "aaa", DeployResult::Deployed);
+        control.record_deploy(&settings, "api", "bbb", DeployResult::Failed);
+        control.record_deploy(&settings, "web", "bbb", DeployResult::Unchanged);
+        let other = RepositorySettings {
+            branch: "develop".to_owned(),
+            ..settings.clone()
+        };
+        control.record_deploy(&other, "api", "ccc", DeployResult::Deployed);
+        assert_eq!(control.last_deployed(&settings).unwrap().commit, "aaa");
+    }
+
+    #[tokio::test]
+    async fn test_socket_round_trip() {
+        let dir = env::temp_dir().join(format!("deployer-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("deployer.sock");
+        let path = path.to_str().unwrap();
+        let listener = bind(path).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(Path::new(path)), 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(bind(path).is_err());
+        let control = Arc::new(Control::new("", config()));
+        tokio::spawn(serve(listener, control));
+
+        match send(path, &Request::Status).await.unwrap() {
+            Response::Status(status) => {
+                assert_eq!(status.repositories[0].branch, "main");
+                assert_eq!(status.repositories[0].services[0].state, "pending");
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+
+        // Not a socket, never removed.
+        let file = dir.join("file");
+        fs::write(&file, "keep").unwrap();
+        assert!(bind(file.to_str().unwrap()).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+// Deploy history. Every deploy of a service is recorded with
+// its repository, environment and result. Kept in memory and,
+// with `history_file`, appended to a JSON lines file that is
+// read back on start.
+
+use crate::log;
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Entries kept in memory. The file is compacted
+/// to this many entries once it grows past twice as many.
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Deploy {
+    pub repository: String,
+    pub branch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    pub service: String,
+    pub commit: String,
+    /// RFC 3339.
+    pub time: String,
+    pub result: DeployResult,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployResult {
+    Deployed,
+    Failed,
+    /// Nothing the service is built from changed.
+    Unchanged,
+}
+
+impl Display for DeployResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let result = match self {
+            Self::Deployed => "deployed",
+            Self::Failed => "failed",
+            Self::Unchanged => "unchanged",
+        };
+        write!(f, "{}", result)
+    }
+}
+
+impl Display for Deploy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.time,
+            self.environment.as_deref().unwrap_or("-"),
+            self.service,
+            self.commit,
+            self.result,
+            self.repository
+        )
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    deploys: VecDeque<Deploy>,
+    /// Lines in the file, to know when to compact it.
+    lines: usize,
+}
+
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Mutex<Entries>,
+}
+
+impl History {
+    /// History kept in `path`, if any. Entries already
+    /// in the file are loaded, unreadable lines skipped.
+    pub fn open(path: Option<&str>) -> Self {
+        let mut entries = Entries::default();
+        if let Some(path) = path {
+            match read(Path::new(path)) {
+                Ok(deploys) => {
+                    entries.lines = deploys.len();
+                    let skip = deploys.len().saturating_sub(MAX_ENTRIES);
+                    entries.deploys = deploys.into_iter().skip(skip).collect();
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    log!("Failed to read deploy history {}: {}", path, e);
+                }
+            }
+        }
+        History {
+            path: path.map(PathBuf::from),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    pub fn record(&self, deploy: Deploy) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.deploys.len() == MAX_ENTRIES {
+            entries.deploys.pop_front();
+        }
+        entries.deploys.push_back(deploy.clone());
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = if entries.lines >= 2 * MAX_ENTRIES {
+            entries.lines = entries.deploys.len();
+            compact(path, &entries.deploys)
+        } else {
+            entries.lines += 1;
+            append(path, &deploy)
+        };
+        if let Err(e) = result {
+            log!("Failed to write deploy history {}: {}", path.display(), e);
+        }
+    }
+
+    /// Up to `limit` latest deploys, oldest first.
+    pub fn latest(&self, limit: usize) -> Vec<Deploy> {
+        let entries = self.entries.lock().unwrap();
+        let skip = entries.deploys.len().saturating_sub(limit);
+        entries.deploys.iter().skip(skip).cloned().collect()
+    }
+
+    /// Latest deploy of `service`.
+    pub fn last(&self, service: &str) -> Option<Deploy> {
+        self.find(|d| d.service == service)
+    }
+
+    /// Latest deploy `matches` accepts.
+    pub fn find(&self, matches: impl Fn(&Deploy) -> bool) -> Option<Deploy> {
+        let entries = self.entries.lock().unwrap();
+        entries.deploys.iter().rev().find(|d| matches(d)).cloned()
+    }
+}
+
+fn read(path: &Path) -> io::Result<Vec<Deploy>> {
+    let file = File::open(path)?;
+    let mut deploys = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(deploy) = serde_json::from_str(&line?) {
+            deploys.push(deploy);
+        }
+    }
+    Ok(deploys)
+}
+
+fn append(path: &Path, deploy: &Deploy) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_vec(deploy)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// Rewrite the file with `deploys` only.
+fn compact(path: &Path, deploys: &VecDeque<Deploy>) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for deploy in deploys {
+        let mut line = serde_json::to_vec(deploy)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+    }
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn deploy(service: &str, environment: &str, result: DeployResult) -> Deploy {
+        Deploy {
+            repository: "github.com/a/b".to_owned(),
+            branch: "main".to_owned(),
+            environment: Some(environment.to_owned()),
+            service: service.to_owned(),
+            commit: "0123456".to_owned(),
+            time: Local::now().to_rfc3339(),
+            result,
+        }
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = env::temp_dir().join(format!("deployer-history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        let history = History::open(Some(path));
+        history.record(deploy("api-staging", "staging", DeployResult::Deployed));
+        history.record(deploy("api-production", "production", DeployResult::Failed));
+        history.record(deploy("api-staging", "staging", DeployResult::Failed));
+
+        // Read back after a restart.
+        let history = History::open(Some(path));
+        assert_eq!(history.latest(10).len(), 3);
+        assert_eq!(history.latest(1)[0].service, "api-staging");
+        let last = history.last("api-production").unwrap();
+        assert_eq!(last.environment.as_deref(), Some("production"));
+        assert_eq!(last.result, DeployResult::Failed);
+        let deployed = history
+            .find(|d| d.result == DeployResult::Deployed)
+            .unwrap();
+        assert_eq!(deployed.service, "api-staging");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+// Where repositories are hosted. A `SourceProvider` knows the
+// API of one forge: where to look up the latest commit, tags and
+// releases, how to authenticate and where to clone from. The
+// polling loop in `pull` only talks to this trait.
+
+use super::pull::{
+    checks::{Check, CheckSource},
+    tags::Tag,
+};
+use crate::generate_conf::file_struct::{RepositorySettings, TrackSettings, TrackSource};
+use git2::{Cred, CredentialType, Direction, Remote, RemoteCallbacks, Repository};
+use reqwest::RequestBuilder;
+use std::path::{Path, PathBuf};
+
+pub mod git;
+pub mod gitea;
+pub mod github;
+pub mod github_app;
+pub mod gitlab;
+
+pub use git::Git;
+pub use gitea::Gitea;
+pub use github::GitHub;
+pub use gitlab::GitLab;
+
+/// Providers `provider` can be set to.
+pub const PROVIDERS: [&str; 5] = ["github", "gitlab", "gitea", "forgejo", "git"];
+
+/// Providers that work without a token.
+pub fn needs_token(provider: &str) -> bool {
+    provider != "git"
+}
+
+/// How to authenticate to a git remote.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// User for HTTPS and SSH, unless the URL names one.
+    pub username: String,
+    /// Password or token for HTTPS.
+    pub password: String,
+    /// Private key for SSH. Without it, ssh-agent is asked.
+    pub ssh_key: Option<String>,
+}
+
+impl Credentials {
+    /// Callbacks answering what the remote asks for. Gives
+    /// up after a few attempts instead of asking forever.
+    pub fn callbacks(&self) -> RemoteCallbacks<'_> {
+        let mut attempts = 0;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(move |_, username, allowed| {
+            attempts += 1;
+            if attempts > 3 {
+                return Err(git2::Error::from_str("authentication failed"));
+            }
+            let username = username.unwrap_or(&self.username);
+            if allowed.contains(CredentialType::SSH_KEY) {
+                match &self.ssh_key {
+                    Some(key) => Cred::ssh_key(username, None, Path::new(key), None),
+                    None => Cred::ssh_key_from_agent(username),
+                }
+            } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+                Cred::userpass_plaintext(username, &self.password)
+            } else if allowed.contains(CredentialType::USERNAME) {
+                Cred::username(username)
+            } else {
+                Cred::default()
+            }
+        });
+        callbacks
+    }
+}
+
+/// Where providers without an API read refs from.
+#[derive(Debug, Clone)]
+pub enum Refs {
+    /// `git ls-remote`.
+    Remote {
+        url: String,
+        credentials: Credentials,
+    },
+    /// Repository on this machine, bare or a working tree.
+    Local(PathBuf),
+}
+
+impl Refs {
+    /// Name and SHA of every ref. Blocks.
+    pub fn list(&self) -> Result<Vec<(String, String)>, git2::Error> {
+        match self {
+            Refs::Remote { url, credentials } => {
+                let mut remote = Remote::create_detached(url.as_str())?;
+                let connection =
+                    remote.connect_auth(Direction::Fetch, Some(credentials.callbacks()), None)?;
+                Ok(connection
+                    .list()?
+                    .iter()
+                    .map(|head| (head.name().to_owned(), head.oid().to_string()))
+                    .collect())
+            }
+            Refs::Local(path) => {
+                let repository = Repository::open(path)?;
+                let mut refs = Vec::new();
+                for reference in repository.references()? {
+                    let reference = reference?;
+                    let (Some(name), Some(oid)) = (reference.name(), reference.target()) else {
+                        continue;
+                    };
+                    refs.push((name.to_owned(), oid.to_string()));
+                    // Listed like `ls-remote` does: the commit of
+                    // an annotated tag follows as `<name>^{}`.
+                    if let Ok(commit) = reference.peel_to_commit() {
+                        if commit.id() != oid {
+                            refs.push((format!("{}^{{}}", name), commit.id().to_string()));
+                        }
+                    }
+                }
+                Ok(refs)
+            }
+        }
+    }
+}
+
+/// Every method but `refs`, `clone_url`, `credentials` and
+/// `full_name` is about the REST API and only used if
+/// `refs` is `None`.
+pub trait SourceProvider: Send + Sync {
+    /// How webhook payloads refer to the repository, `owner/name`.
+    fn full_name(&self) -> &str;
+
+    /// Refs to poll instead of asking an API.
+    fn refs(&self) -> Option<Refs> {
+        None
+    }
+
+    /// API URL answering with the latest commit of `branch`.
+    fn commit_url(&self, branch: &str) -> String;
+
+    /// SHA from the response of `commit_url`.
+    fn commit(&self, body: &str) -> Result<String, String>;
+
+    /// API URL answering with the commit of tag `name`.
+    fn tag_url(&self, name: &str) -> String {
+        self.commit_url(name)
+    }
+
+    /// SHA from the response of `tag_url`.
+    fn tag_commit(&self, body: &str) -> Result<String, String> {
+        self.commit(body)
+    }
+
+    /// API URL listing the latest tags or releases.
+    fn tags_url(&self, source: TrackSource) -> String;
+
+    /// Tags from the response of `tags_url`, drafts left out.
+    fn tags(&self, body: &str, source: TrackSource) -> Result<Vec<Tag>, String>;
+
+    /// API URL listing the results of CI for commit `sha`,
+    /// `None` if the provider has no such thing.
+    fn checks_url(&self, _sha: &str, _source: CheckSource) -> Option<String> {
+        None
+    }
+
+    /// Checks from the response of `checks_url`.
+    fn checks(&self, _body: &str, _source: CheckSource) -> Result<Vec<Check>, String> {
+        Ok(Vec::new())
+    }
+
+    /// URL to clone the repository from.
+    fn clone_url(&self) -> String;
+
+    /// Credentials to clone with.
+    fn credentials(&self) -> Credentials;
+
+    /// Add the token to an API request.
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder;
+}
+
+/// Provider of `settings.provider` for `settings.repository`.
+/// Fails if either of them is not valid.
+pub fn new(settings: &RepositorySettings) -> Result<Box<dyn SourceProvider>, String> {
+    match settings.provider.as_str() {
+        "github" => Ok(Box::new(GitHub::new(settings)?)),
+        "gitlab" => Ok(Box::new(GitLab::new(settings)?)),
+        // Forgejo is a fork of Gitea and keeps its API.
+        "gitea" | "forgejo" => Ok(Box::new(Gitea::new(settings)?)),
+        "git" => Ok(Box::new(Git::new(settings)?)),
+        provider => Err(format!("unknown provider \"{}\"", provider)),
+    }
+}
+
+/// API URL polled for `settings`: the commit of
+/// the branch, or tags or releases with `track`.
+pub fn latest_url(
+    provider: &dyn SourceProvider,
+    branch: &str,
+    track: Option<&TrackSettings>,
+) -> Result<String, String> {
+    match track {
+        Some(track) => Ok(provider.tags_url(track.source)),
+        None if branch.is_empty() => Err("No main branch specified!".to_owned()),
+        None => Ok(provider.commit_url(branch)),
+    }
+}
+
+/// Split the base URL of `base_url` or, without it, `https://`
+/// and the host of `repository` from the path of the repository.
+fn split_repository<'a>(
+    repository: &'a str,
+    base_url: Option<&str>,
+) -> Result<(String, &'a str), String> {
+    let (host, path) = repository
+        .split_once('/')
+        .ok_or_else(|| "Invalid repository URL!".to_owned())?;
+    if host.is_empty() || path.is_empty() || path.split('/').any(str::is_empty) {
+        return Err("Invalid repository URL!".to_owned());
+    }
+    let base = match base_url {
+        Some(base) => base.trim_end_matches('/').to_owned(),
+        None => format!("https://{}", host),
+    };
+    Ok((base, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let settings = |provider: &str, repository: &str| RepositorySettings {
+            provider: provider.to_owned(),
+            repository: repository.to_owned(),
+            ..RepositorySettings::default()
+        };
+        assert!(new(&settings("github", "github.com/a/b")).is_ok());
+        assert!(new(&settings("gitlab", "gitlab.com/group/sub/project")).is_ok());
+        assert!(new(&settings("forgejo", "codeberg.org/a/b")).is_ok());
+        assert!(new(&settings("gitea", "git.example.com/a/b/c")).is_err());
+        assert_eq!(
+            new(&settings("bitbucket", "bitbucket.org/a/b"))
+                .err()
+                .unwrap(),
+            "unknown provider \"bitbucket\""
+        );
+
+        let provider = new(&settings("github", "github.com/a/b")).unwrap();
+        assert_eq!(
+            latest_url(provider.as_ref(), "main", None).unwrap(),
+            "https://api.github.com/repos/a/b/commits/main"
+        );
+        assert!(latest_url(provider.as_ref(), "", None).is_err());
+    }
+
+    #[test]
+    fn test_split_repository() {
+        assert_eq!(
+            split_repository("gitlab.example.com/group/project", None).unwrap(),
+            ("https://gitlab.example.com".to_owned(), "group/project")
+        );
+        assert_eq!(
+            split_repository(
+                "gitlab.example.com/group/project",
+                Some("http://10.0.0.1:8080/")
+            )
+            .unwrap(),
+            ("http://10.0.0.1:8080".to_owned(), "group/project")
+        );
+        assert!(split_repository("gitlab.example.com", None).is_err());
+        assert!(split_repository("gitlab.example.com/group//project", None).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+// Any git remote, without an API. New commits are found by listing
+// the refs of the remote the way `git ls-remote` does, so nothing
+// but the git protocol is needed: no token and no known host.
+// Repositories on this machine are read directly, without any network.
+
+use super::{Credentials, Refs, SourceProvider};
+use crate::generate_conf::file_struct::{RepositorySettings, TrackSettings, TrackSource};
+use crate::run_deployer::pull::tags::{self, Tag};
+use reqwest::RequestBuilder;
+use std::path::PathBuf;
+
+pub struct Git {
+    /// URL as written in `repository`.
+    url: String,
+    /// Path of a repository on this machine.
+    local: Option<PathBuf>,
+    credentials: Credentials,
+}
+
+impl Git {
+    /// The repository is any URL git understands: `https://`,
+    /// `ssh://`, `git@host:path` or `host/path` for HTTPS. Local
+    /// repositories are `file:///path` or an absolute path.
+    pub fn new(settings: &RepositorySettings) -> Result<Self, String> {
+        let repository = settings.repository.trim();
+        if repository.is_empty() {
+            return Err("Invalid repository URL!".to_owned());
+        }
+        let local = match repository.strip_prefix("file://") {
+            Some(path) if path.starts_with('/') => Some(PathBuf::from(path)),
+            Some(_) => return Err("Invalid repository URL!".to_owned()),
+            None if repository.starts_with('/') => Some(PathBuf::from(repository)),
+            None => None,
+        };
+        let url = if local.is_some() || repository.contains(':') {
+            repository.to_owned()
+        } else {
+            format!("https://{}", repository)
+        };
+        Ok(Git {
+            url,
+            local,
+            credentials: Credentials {
+                username: settings
+                    .username
+                    .clone()
+                    .unwrap_or_else(|| "git".to_owned()),
+                password: settings.token.clone(),
+                ssh_key: settings.ssh_key.clone(),
+            },
+        })
+    }
+}
+
+const NO_API: &str = "provider \"git\" has no API";
+
+impl SourceProvider for Git {
+    fn full_name(&self) -> &str {
+        &self.url
+    }
+
+    fn refs(&self) -> Option<Refs> {
+        Some(match &self.local {
+            Some(path) => Refs::Local(path.clone()),
+            None => Refs::Remote {
+                url: self.url.clone(),
+                credentials: self.credentials.clone(),
+            },
+        })
+    }
+
+    fn commit_url(&self, _: &str) -> String {
+        self.url.clone()
+    }
+
+    fn commit(&self, _: &str) -> Result<String, String> {
+        Err(NO_API.to_owned())
+    }
+
+    fn tags_url(&self, _: TrackSource) -> String {
+        self.url.clone()
+    }
+
+    fn tags(&self, _: &str, _: TrackSource) -> Result<Vec<Tag>, String> {
+        Err(NO_API.to_owned())
+    }
+
+    fn clone_url(&self) -> String {
+        self.url.clone()
+    }
+
+    fn credentials(&self) -> Credentials {
+        self.credentials.clone()
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+    }
+}
+
+/// Tag or branch to deploy out of the refs listed by `Refs::list`.
+/// `Ok(None)` if no tag matches `track`.
+pub fn latest(
+    refs: &[(String, String)],
+    branch: &str,
+    track: Option<&TrackSettings>,
+) -> Result<Option<Tag>, String> {
+    let track = match track {
+        None => {
+            let name = format!("refs/heads/{}", branch);
+            return refs
+                .iter()
+                .find(|(r, _)| *r == name)
+                .map(|(_, sha)| {
+                    Some(Tag {
+                        name: branch.to_owned(),
+                        sha: Some(sha.clone()),
+                        prerelease: false,
+                        assets: Vec::new(),
+                    })
+                })
+                .ok_or_else(|| format!("branch {} not found", branch));
+        }
+        Some(track) if track.source == TrackSource::Releases => {
+            return Err("releases can't be tracked with provider \"git\"".to_owned())
+        }
+        Some(track) => track,
+    };
+
+    let mut found: Vec<Tag> = Vec::new();
+    for (name, sha) in refs {
+        let Some(name) = name.strip_prefix("refs/tags/") else {
+            continue;
+        };
+        // Annotated tags are listed twice, `^{}` is the commit.
+        let (name, peeled) = match name.strip_suffix("^{}") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        match found.iter_mut().find(|tag| tag.name == name) {
+            Some(tag) if peeled => tag.sha = Some(sha.clone()),
+            Some(_) => {}
+            None => found.push(Tag {
+                name: name.to_owned(),
+                sha: Some(sha.clone()),
+                prerelease: false,
+                assets: Vec::new(),
+            }),
+        }
+    }
+    tags::newest(track, found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs() -> Vec<(String, String)> {
+        [
+            ("HEAD", "aaa"),
+            ("refs/heads/main", "aaa"),
+            ("refs/tags/v1.0.0", "bbb"),
+            ("refs/tags/v1.1.0", "ccc"),
+            ("refs/tags/v1.1.0^{}", "ddd"),
+        ]
+        .iter()
+        .map(|(name, sha)| (name.to_string(), sha.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_new() {
+        let git = |repository: &str| {
+            Git::new(&RepositorySettings {
+                provider: "git".to_owned(),
+                repository: repository.to_owned(),
+                token: String::new(),
+                ..RepositorySettings::default()
+            })
+            .unwrap()
+            .clone_url()
+        };
+        assert_eq!(git("git.example.com/app"), "https://git.example.com/app");
+        assert_eq!(git("git@host:team/app.git"), "git@host:team/app.git");
+        assert_eq!(git("ssh://git@host/app.git"), "ssh://git@host/app.git");
+        assert_eq!(git("/srv/git/app.git"), "/srv/git/app.git");
+        assert_eq!(git("file:///srv/git/app.git"), "file:///srv/git/app.git");
+    }
+
+    #[test]
+    fn test_list_refs() {
+        let dir = std::env::temp_dir().join(format!("deployer-ls-remote-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let origin = git2::Repository::init(&dir).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = origin
+            .find_tree(origin.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = origin
+            .commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        let object = origin.find_object(commit, None).unwrap();
+        origin
+            .tag("v1.0.0", &object, &signature, "v1.0.0", false)
+            .unwrap();
+        let branch = origin.head().unwrap().shorthand().unwrap().to_owned();
+
+        let git = Git::new(&RepositorySettings {
+            provider: "git".to_owned(),
+            repository: format!("file://{}", dir.display()),
+            token: String::new(),
+            ..RepositorySettings::default()
+        })
+        .unwrap();
+        let remote = Refs::Remote {
+            url: dir.to_string_lossy().into_owned(),
+            credentials: Credentials::default(),
+        };
+        let track = TrackSettings {
+            source: TrackSource::Tags,
+            pattern: None,
+            version: None,
+            prereleases: false,
+        };
+        // Read directly and over the local transport, the same refs.
+        for refs in [git.refs().unwrap(), remote] {
+            let refs = refs.list().unwrap();
+            let head = latest(&refs, &branch, None).unwrap().unwrap();
+            assert_eq!(head.sha.unwrap(), commit.to_string());
+            let tag = latest(&refs, "", Some(&track)).unwrap().unwrap();
+            assert_eq!(tag.sha.unwrap(), commit.to_string());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_latest() {
+        let sha = |tag: Option<Tag>| tag.unwrap().sha.unwrap();
+        assert_eq!(sha(latest(&refs(), "main", None).unwrap()), "aaa");
+        assert!(latest(&refs(), "dev", None).is_err());
+
+        let track = TrackSettings {
+            source: TrackSource::Tags,
+            pattern: None,
+            version: None,
+            prereleases: false,
+        };
+        let tag = latest(&refs(), "", Some(&track)).unwrap().unwrap();
+        assert_eq!(
+            (tag.name.as_str(), tag.sha.unwrap().as_str()),
+            ("v1.1.0", "ddd")
+        );
+
+        let releases = TrackSettings {
+            source: TrackSource::Releases,
+            ..track
+        };
+        assert!(latest(&refs(), "", Some(&releases)).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+// Gitea's REST API (v1), also spoken by Forgejo.
+
+use super::{split_repository, Credentials, SourceProvider};
+use crate::generate_conf::file_struct::{RepositorySettings, TrackSource};
+use crate::run_deployer::{
+    pull::asset::Asset,
+    pull::checks::{Check, CheckSource, CheckState},
+    pull::tags::Tag,
+};
+use reqwest::RequestBuilder;
+use serde_derive::Deserialize;
+
+/// `GET /repos/{owner}/{repo}/branches/{branch}`.
+#[derive(Debug, Deserialize)]
+struct BranchResponse {
+    commit: BranchCommit,
+}
+
+#[derive(Debug, Deserialize)]
+struct BranchCommit {
+    id: String,
+}
+
+/// `GET /repos/{owner}/{repo}/tags/{tag}` and
+/// element of `GET /repos/{owner}/{repo}/tags`.
+#[derive(Debug, Deserialize)]
+struct TagResponse {
+    name: String,
+    commit: TagCommit,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagCommit {
+    sha: String,
+}
+
+/// Element of `GET /repos/{owner}/{repo}/releases`.
+#[derive(Debug, Deserialize)]
+struct ReleaseResponse {
+    tag_name: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// `GET /repos/{owner}/{repo}/commits/{sha}/status`.
+#[derive(Debug, Deserialize)]
+struct CombinedStatus {
+    #[serde(default)]
+    statuses: Vec<Status>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    #[serde(default)]
+    id: u64,
+    context: String,
+    status: String,
+}
+
+pub struct Gitea {
+    /// `https://git.example.com`, without a trailing slash.
+    base: String,
+    /// `owner/repo`.
+    full_name: String,
+    token: String,
+}
+
+impl Gitea {
+    /// The repository is `host/owner/repo`. The instance is
+    /// reached at `https://host` unless `base_url` is set.
+    pub fn new(settings: &RepositorySettings) -> Result<Self, String> {
+        let (base, path) = split_repository(&settings.repository, settings.base_url.as_deref())?;
+        let path = path.trim_end_matches(".git");
+        if path.split('/').count() != 2 {
+            return Err("Invalid repository URL!".to_owned());
+        }
+        Ok(Gitea {
+            base,
+            full_name: path.to_owned(),
+            token: settings.token.clone(),
+        })
+    }
+
+    fn api(&self) -> String {
+        format!("{}/api/v1/repos/{}", self.base, self.full_name)
+    }
+}
+
+impl SourceProvider for Gitea {
+    fn full_name(&self) -> &str {
+        &self.full_name
+    }
+
+    fn commit_url(&self, branch: &str) -> String {
+        format!("{}/branches/{}", self.api(), branch)
+    }
+
+    fn commit(&self, body: &str) -> Result<String, String> {
+        serde_json::from_str::<BranchResponse>(body)
+            .map(|branch| branch.commit.id)
+            .map_err(|e| e.to_string())
+    }
+
+    fn tag_url(&self, name: &str) -> String {
+        format!("{}/tags/{}", self.api(), name)
+    }
+
+    fn tag_commit(&self, body: &str) -> Result<String, String> {
+        serde_json::from_str::<TagResponse>(body)
+            .map(|tag| tag.commit.sha)
+            .map_err(|e| e.to_string())
+    }
+
+    fn tags_url(&self, source: TrackSource) -> String {
+        // 50 is the default maximum page size of Gitea.
+        match source {
+            TrackSource::Tags => format!("{}/tags?limit=50", self.api()),
+            TrackSource::Releases => format!("{}/releases?limit=50", self.api()),
+        }
+    }
+
+    fn tags(&self, body: &str, source: TrackSource) -> Result<Vec<Tag>, String> {
+        let tags = match source {
+            TrackSource::Tags => serde_json::from_str::<Vec<TagResponse>>(body)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|t| Tag {
+                    name: t.name,
+                    sha: Some(t.commit.sha),
+                    prerelease: false,
+                    assets: Vec::new(),
+                })
+                .collect(),
+            TrackSource::Releases => serde_json::from_str::<Vec<ReleaseResponse>>(body)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .filter(|r| !r.draft)
+                .map(|r| Tag {
+                    name: r.tag_name,
+                    sha: None,
+                    prerelease: r.prerelease,
+                    assets: r
+                        .assets
+                        .into_iter()
+                        .map(|asset| Asset {
+                            name: asset.name,
+                            url: asset.browser_download_url,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        Ok(tags)
+    }
+
+    fn checks_url(&self, sha: &str, source: CheckSource) -> Option<String> {
+        // Gitea Actions report commit statuses too.
+        match source {
+            CheckSource::CheckRuns => None,
+            CheckSource::Statuses => Some(format!("{}/commits/{}/status", self.api(), sha)),
+        }
+    }
+
+    fn checks(&self, body: &str, _: CheckSource) -> Result<Vec<Check>, String> {
+        let statuses = serde_json::from_str::<CombinedStatus>(body)
+            .map_err(|e| e.to_string())?
+            .statuses;
+        Ok(statuses
+            .into_iter()
+            .map(|status| Check {
+                id: status.id,
+                name: status.context,
+                state: match status.status.as_str() {
+                    "success" | "warning" => CheckState::Success,
+                    "pending" => CheckState::Pending,
+                    _ => CheckState::Failure,
+                },
+            })
+            .collect())
+    }
+
+    fn clone_url(&self) -> String {
+        format!("{}/{}.git", self.base, self.full_name)
+    }
+
+    fn credentials(&self) -> Credentials {
+        // Any user name goes with a token as the password.
+        Credentials {
+            username: "oauth2".to_owned(),
+            password: self.token.clone(),
+            ssh_key: None,
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request.header("Authorization", format!("token {}", self.token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forgejo() -> Gitea {
+        Gitea::new(&RepositorySettings {
+            provider: "forgejo".to_owned(),
+            repository: "git.example.com/team/api".to_owned(),
+            token: "token".to_owned(),
+            ..RepositorySettings::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_urls() {
+        let forgejo = forgejo();
+        assert_eq!(
+            forgejo.commit_url("main"),
+            "https://git.example.com/api/v1/repos/team/api/branches/main"
+        );
+        assert_eq!(
+            forgejo.tag_url("v1.0.0"),
+            "https://git.example.com/api/v1/repos/team/api/tags/v1.0.0"
+        );
+        assert_eq!(forgejo.clone_url(), "https://git.example.com/team/api.git");
+        assert_eq!(forgejo.full_name(), "team/api");
+    }
+
+    #[test]
+    fn test_responses() {
+        let forgejo = forgejo();
+        let branch = r#"{ "name": "main", "commit": { "id": "0123abc", "message": "Fix" } }"#;
+        assert_eq!(forgejo.commit(branch).unwrap(), "0123abc");
+        let tag = r#"{ "name": "v1.0.0", "id": "4567", "commit": { "sha": "89ab" } }"#;
+        assert_eq!(forgejo.tag_commit(tag).unwrap(), "89ab");
+
+        let body = r#"[
+            { "tag_name": "v2.0.0", "draft": true, "prerelease": false },
+            { "tag_name": "v1.0.0", "draft": false, "prerelease": false,
+              "assets": [{ "name": "api.tar.gz",
+                           "browser_download_url": "https://git.example.com/a.tar.gz" }] }
+        ]"#;
+        let tags = forgejo.tags(body, TrackSource::Releases).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].assets[0].url, "https://git.example.com/a.tar.gz");
+    }
+}
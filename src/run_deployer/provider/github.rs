@@ -0,0 +1,361 @@
+// GitHub's REST API, on github.com or a GitHub Enterprise Server.
+
+use super::{split_repository, Credentials, SourceProvider};
+use crate::generate_conf::file_struct::{Commit, RepositorySettings, TrackSource};
+use crate::run_deployer::{
+    parse_url,
+    pull::asset::Asset,
+    pull::checks::{Check, CheckSource, CheckState},
+    pull::tags::Tag,
+};
+use reqwest::RequestBuilder;
+use serde_derive::Deserialize;
+
+const API: &str = "https://api.github.com";
+const WEB: &str = "https://github.com";
+
+/// Element of `GET /repos/{owner}/{repo}/tags`.
+#[derive(Debug, Deserialize)]
+struct TagResponse {
+    name: String,
+    commit: Commit,
+}
+
+/// Element of `GET /repos/{owner}/{repo}/releases`.
+#[derive(Debug, Deserialize)]
+struct ReleaseResponse {
+    tag_name: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    assets: Vec<Asset>,
+}
+
+/// `GET /repos/{owner}/{repo}/commits/{sha}/check-runs`.
+#[derive(Debug, Deserialize)]
+struct CheckRunsResponse {
+    check_runs: Vec<CheckRun>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckRun {
+    #[serde(default)]
+    id: u64,
+    name: String,
+    status: String,
+    conclusion: Option<String>,
+}
+
+/// `GET /repos/{owner}/{repo}/commits/{sha}/status`.
+#[derive(Debug, Deserialize)]
+struct CombinedStatus {
+    statuses: Vec<Status>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    #[serde(default)]
+    id: u64,
+    context: String,
+    state: String,
+}
+
+pub struct GitHub {
+    /// `https://api.github.com` or `https://ghe.corp/api/v3`.
+    api: String,
+    /// Where the repository is cloned from, `https://github.com`.
+    web: String,
+    owner: String,
+    name: String,
+    /// `owner/name`.
+    full_name: String,
+    token: String,
+}
+
+impl GitHub {
+    /// Fails if the repository is not `github.com/owner/name`.
+    /// With `base_url` it is `host/owner/name` on a GitHub
+    /// Enterprise Server reached at `base_url`, with or
+    /// without the trailing `/api/v3`.
+    pub fn new(settings: &RepositorySettings) -> Result<Self, String> {
+        let (api, web, owner, name) = match &settings.base_url {
+            None => {
+                let (owner, name) = parse_url(&settings.repository)?;
+                (API.to_owned(), WEB.to_owned(), owner, name)
+            }
+            Some(base_url) => {
+                let (base, path) = split_repository(&settings.repository, Some(base_url))?;
+                let (owner, name) = path
+                    .split_once('/')
+                    .filter(|(_, name)| !name.contains('/'))
+                    .ok_or_else(|| "Invalid repository URL!".to_owned())?;
+                let web = base.trim_end_matches("/api/v3").to_owned();
+                (format!("{}/api/v3", web), web, owner, name)
+            }
+        };
+        Ok(GitHub {
+            api,
+            web,
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            full_name: format!("{}/{}", owner, name),
+            token: settings.token.clone(),
+        })
+    }
+
+    /// `https://api.github.com` or the `/api/v3` of the server.
+    pub fn api_base(&self) -> &str {
+        &self.api
+    }
+
+    /// API URL of the repository.
+    pub fn api(&self) -> String {
+        format!("{}/repos/{}/{}", self.api, self.owner, self.name)
+    }
+}
+
+impl SourceProvider for GitHub {
+    fn full_name(&self) -> &str {
+        &self.full_name
+    }
+
+    fn commit_url(&self, branch: &str) -> String {
+        format!("{}/commits/{}", self.api(), branch)
+    }
+
+    fn commit(&self, body: &str) -> Result<String, String> {
+        serde_json::from_str::<Commit>(body)
+            .map(|commit| commit.sha)
+            .map_err(|e| e.to_string())
+    }
+
+    fn tags_url(&self, source: TrackSource) -> String {
+        match source {
+            TrackSource::Tags => format!("{}/tags?per_page=100", self.api()),
+            TrackSource::Releases => format!("{}/releases?per_page=100", self.api()),
+        }
+    }
+
+    fn tags(&self, body: &str, source: TrackSource) -> Result<Vec<Tag>, String> {
+        let tags = match source {
+            TrackSource::Tags => serde_json::from_str::<Vec<TagResponse>>(body)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|t| Tag {
+                    name: t.name,
+                    sha: Some(t.commit.sha),
+                    prerelease: false,
+                    assets: Vec::new(),
+                })
+                .collect(),
+            // Releases only name their tag.
+            TrackSource::Releases => serde_json::from_str::<Vec<ReleaseResponse>>(body)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .filter(|r| !r.draft)
+                .map(|r| Tag {
+                    name: r.tag_name,
+                    sha: None,
+                    prerelease: r.prerelease,
+                    assets: r.assets,
+                })
+                .collect(),
+        };
+        Ok(tags)
+    }
+
+    fn checks_url(&self, sha: &str, source: CheckSource) -> Option<String> {
+        Some(match source {
+            CheckSource::CheckRuns => {
+                format!("{}/commits/{}/check-runs?per_page=100", self.api(), sha)
+            }
+            CheckSource::Statuses => format!("{}/commits/{}/status?per_page=100", self.api(), sha),
+        })
+    }
+
+    fn checks(&self, body: &str, source: CheckSource) -> Result<Vec<Check>, String> {
+        let checks = match source {
+            CheckSource::CheckRuns => serde_json::from_str::<CheckRunsResponse>(body)
+                .map_err(|e| e.to_string())?
+                .check_runs
+                .into_iter()
+                .map(|run| Check {
+                    id: run.id,
+                    name: run.name,
+                    state: match (run.status.as_str(), run.conclusion.as_deref()) {
+                        ("completed", Some("success" | "neutral" | "skipped")) => {
+                            CheckState::Success
+                        }
+                        ("completed", _) => CheckState::Failure,
+                        _ => CheckState::Pending,
+                    },
+                })
+                .collect(),
+            CheckSource::Statuses => serde_json::from_str::<CombinedStatus>(body)
+                .map_err(|e| e.to_string())?
+                .statuses
+                .into_iter()
+                .map(|status| Check {
+                    id: status.id,
+                    name: status.context,
+                    state: status_state(&status.state),
+                })
+                .collect(),
+        };
+        Ok(checks)
+    }
+
+    fn clone_url(&self) -> String {
+        format!("{}/{}/{}.git", self.web, self.owner, self.name)
+    }
+
+    fn credentials(&self) -> Credentials {
+        Credentials {
+            username: "x-access-token".to_owned(),
+            password: self.token.clone(),
+            ssh_key: None,
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .header("Authorization", format!("token {}", self.token))
+            .header("User-Agent", "request")
+    }
+}
+
+/// State of a commit status: `pending`, `success`, `failure` or `error`.
+fn status_state(state: &str) -> CheckState {
+    match state {
+        "success" => CheckState::Success,
+        "pending" => CheckState::Pending,
+        _ => CheckState::Failure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn github(repository: &str) -> Result<GitHub, String> {
+        GitHub::new(&RepositorySettings {
+            repository: repository.to_owned(),
+            ..RepositorySettings::default()
+        })
+    }
+
+    #[test]
+    fn test_valid_url() {
+        let github = github("github.com/Makefolder/deployer").unwrap();
+        assert_eq!(
+            github.commit_url("master"),
+            "https://api.github.com/repos/Makefolder/deployer/commits/master"
+        );
+        assert_eq!(
+            github.tags_url(TrackSource::Releases),
+            "https://api.github.com/repos/Makefolder/deployer/releases?per_page=100"
+        );
+        assert_eq!(
+            github.clone_url(),
+            "https://github.com/Makefolder/deployer.git"
+        );
+    }
+
+    #[test]
+    fn test_enterprise_server() {
+        for base_url in ["https://ghe.corp", "https://ghe.corp/api/v3/"] {
+            let github = GitHub::new(&RepositorySettings {
+                repository: "ghe.corp/team/api".to_owned(),
+                base_url: Some(base_url.to_owned()),
+                ..RepositorySettings::default()
+            })
+            .unwrap();
+            assert_eq!(
+                github.commit_url("main"),
+                "https://ghe.corp/api/v3/repos/team/api/commits/main"
+            );
+            assert_eq!(github.clone_url(), "https://ghe.corp/team/api.git");
+        }
+        let result = GitHub::new(&RepositorySettings {
+            repository: "ghe.corp/team/sub/api".to_owned(),
+            base_url: Some("https://ghe.corp".to_owned()),
+            ..RepositorySettings::default()
+        });
+        assert_eq!(result.err().unwrap(), "Invalid repository URL!");
+    }
+
+    #[test]
+    fn test_checks() {
+        let github = github("github.com/a/b").unwrap();
+        assert_eq!(
+            github.checks_url("abc", CheckSource::CheckRuns).unwrap(),
+            "https://api.github.com/repos/a/b/commits/abc/check-runs?per_page=100"
+        );
+        let body = r#"{ "total_count": 3, "check_runs": [
+            { "name": "build", "status": "completed", "conclusion": "success" },
+            { "name": "test", "status": "completed", "conclusion": "timed_out" },
+            { "name": "deploy-preview", "status": "in_progress", "conclusion": null }
+        ] }"#;
+        let states: Vec<_> = github
+            .checks(body, CheckSource::CheckRuns)
+            .unwrap()
+            .into_iter()
+            .map(|check| check.state)
+            .collect();
+        assert_eq!(
+            states,
+            [
+                CheckState::Success,
+                CheckState::Failure,
+                CheckState::Pending
+            ]
+        );
+        let body =
+            r#"{ "state": "pending", "statuses": [{ "context": "ci/lint", "state": "error" }] }"#;
+        assert_eq!(
+            github.checks(body, CheckSource::Statuses).unwrap(),
+            [Check {
+                id: 0,
+                name: "ci/lint".to_owned(),
+                state: CheckState::Failure
+            }]
+        );
+    }
+
+    #[test]
+    fn test_invalid_domain_url() {
+        let result = github("gitlab.com/Makefolder/deployer");
+        assert_eq!(result.err().unwrap(), "Invalid repository domain!");
+    }
+
+    #[test]
+    fn test_invalid_author_url() {
+        let result = github("github.com//deployer");
+        assert_eq!(result.err().unwrap(), "Invalid repository URL!");
+    }
+
+    #[test]
+    fn test_invalid_name_url() {
+        let result = github("github.com/Makefolder/");
+        assert_eq!(result.err().unwrap(), "Invalid repository URL!");
+    }
+
+    #[test]
+    fn test_releases() {
+        let body = r#"[
+            { "tag_name": "v3.0.0", "draft": true, "prerelease": false },
+            { "tag_name": "v2.2.0", "draft": false, "prerelease": true },
+            { "tag_name": "v2.1.0", "draft": false, "prerelease": false,
+              "assets": [{ "name": "api.tar.gz", "url": "https://api.github.com/assets/1" }] }
+        ]"#;
+        let tags = github("github.com/a/b")
+            .unwrap()
+            .tags(body, TrackSource::Releases)
+            .unwrap();
+        assert_eq!(tags.len(), 2);
+        assert!(tags[0].prerelease);
+        assert_eq!(tags[1].assets[0].name, "api.tar.gz");
+    }
+}
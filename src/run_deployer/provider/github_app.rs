@@ -0,0 +1,251 @@
+// GitHub App authentication. A JWT signed with the private key of
+// the app is exchanged for an installation token, which is used
+// like a personal access token until shortly before it expires.
+
+use super::GitHub;
+use crate::error::DeployerError;
+use crate::generate_conf::file_struct::{GitHubAppSettings, RepositorySettings};
+use crate::secrets;
+use chrono::{DateTime, TimeDelta, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{header::ACCEPT, Client, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+
+/// Tokens are replaced this long before they expire.
+const REFRESH_BEFORE: TimeDelta = TimeDelta::minutes(5);
+
+#[derive(Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
+
+/// `POST /app/installations/{installation_id}/access_tokens`.
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
+    expires_at: String,
+}
+
+/// Installation token of a repository, kept between polls.
+#[derive(Default)]
+pub struct Installation {
+    token: Option<(String, DateTime<Utc>)>,
+}
+
+impl Installation {
+    /// Token to authenticate `settings` with: `token`, or
+    /// an installation token with `github_app`. A new one is
+    /// minted if there is none yet or it is about to expire.
+    ///
+    /// Fails with the status GitHub answered with, 401, 403
+    /// and 404 mean the app or installation is wrong.
+    pub async fn token(
+        &mut self,
+        settings: &RepositorySettings,
+        client: &Client,
+    ) -> Result<String, DeployerError> {
+        let Some(app) = &settings.github_app else {
+            return Ok(settings.token.clone());
+        };
+        if let Some((token, expires_at)) = &self.token {
+            if *expires_at - REFRESH_BEFORE > Utc::now() {
+                return Ok(token.clone());
+            }
+        }
+        let github = GitHub::new(settings).map_err(|message| DeployerError::Provider {
+            url: settings.repository.clone(),
+            status: None,
+            message,
+        })?;
+        let (token, expires_at) = mint(app, github.api_base(), client).await?;
+        secrets::register(&token);
+        self.token = Some((token.clone(), expires_at));
+        Ok(token)
+    }
+}
+
+/// JWT of the app, valid for nine minutes. GitHub
+/// allows ten and `iat` is a minute early for clock drift.
+fn jwt(app: &GitHubAppSettings) -> Result<String, String> {
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        iat: now - 60,
+        exp: now + 9 * 60,
+        iss: app.app_id.to_string(),
+    };
+    let key = EncodingKey::from_rsa_pem(app.private_key.as_bytes())
+        .map_err(|e| format!("invalid private key of the GitHub App: {}", e))?;
+    jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key)
+        .map_err(|e| format!("Failed to sign the GitHub App JWT: {}", e))
+}
+
+/// Exchange the JWT for a token of the installation.
+async fn mint(
+    app: &GitHubAppSettings,
+    api: &str,
+    client: &Client,
+) -> Result<(String, DateTime<Utc>), DeployerError> {
+    let url = format!(
+        "{}/app/installations/{}/access_tokens",
+        api, app.installation_id
+    );
+    let error = |status: Option<StatusCode>, message: String| DeployerError::Provider {
+        url: url.clone(),
+        status: status.map(|status| status.as_u16()),
+        message,
+    };
+    let res = client
+        .post(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", jwt(app).map_err(|e| error(None, e))?),
+        )
+        .header(ACCEPT, "application/vnd.github+json")
+        .header("User-Agent", "request")
+        .send()
+        .await
+        .map_err(|e| error(None, e.to_string()))?;
+    let status = res.status();
+    let message = match status {
+        StatusCode::CREATED | StatusCode::OK => None,
+        StatusCode::UNAUTHORIZED => {
+            Some("GitHub rejected the GitHub App, check app_id and the key".to_owned())
+        }
+        StatusCode::FORBIDDEN => Some(format!(
+            "the GitHub App may not use installation {}",
+            app.installation_id
+        )),
+        StatusCode::NOT_FOUND => Some(format!(
+            "installation {} of the GitHub App does not exist",
+            app.installation_id
+        )),
+        _ => Some("Failed to mint an installation token".to_owned()),
+    };
+    if let Some(message) = message {
+        return Err(error(Some(status), message));
+    }
+    let body = res
+        .text()
+        .await
+        .map_err(|e| error(Some(status), format!("Failed to read response: {e}")))?;
+    let response = serde_json::from_str::<TokenResponse>(&body)
+        .map_err(|e| error(Some(status), format!("Unexpected response: {e}")))?;
+    let expires_at = DateTime::parse_from_rfc3339(&response.expires_at)
+        .map_err(|e| error(Some(status), format!("Unexpected response: {e}")))?;
+    Ok((response.token, expires_at.with_timezone(&Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_deployer::stand_in::StandIn;
+    use jsonwebtoken::{DecodingKey, Validation};
+    use std::{
+        fs,
+        process::{Command, Stdio},
+    };
+
+    /// A fresh RSA key pair in PEM, private key first.
+    fn keygen() -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("deployer-github-app-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("key.pem");
+        let openssl = |args: &[&str]| {
+            let status = Command::new("openssl")
+                .args(args)
+                .current_dir(&dir)
+                .stderr(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        openssl(&["genrsa", "-out", "key.pem", "2048"]);
+        openssl(&["rsa", "-in", "key.pem", "-pubout", "-out", "key.pub"]);
+        let pair = (
+            fs::read_to_string(&key).unwrap(),
+            fs::read_to_string(key.with_extension("pub")).unwrap(),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        pair
+    }
+
+    fn settings(url: &str, installation_id: u64, private_key: &str) -> RepositorySettings {
+        RepositorySettings {
+            repository: "ghe.corp/team/api".to_owned(),
+            base_url: Some(url.to_owned()),
+            token: String::new(),
+            github_app: Some(GitHubAppSettings {
+                app_id: 42,
+                installation_id,
+                private_key_file: String::new(),
+                private_key: private_key.to_owned(),
+            }),
+            ..RepositorySettings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_installation_token() {
+        let github = StandIn::start(vec![
+            (
+                "/api/v3/app/installations/7/access_tokens",
+                201,
+                br#"{ "token": "ghs_valid", "expires_at": "2099-01-01T00:00:00Z" }"#.to_vec(),
+            ),
+            (
+                "/api/v3/app/installations/8/access_tokens",
+                201,
+                br#"{ "token": "ghs_expired", "expires_at": "2000-01-01T00:00:00Z" }"#.to_vec(),
+            ),
+        ])
+        .await;
+        let client = Client::new();
+        let (private_key, public_key) = keygen();
+
+        let mut installation = Installation::default();
+        let settings = settings(&github.url, 7, &private_key);
+        for _ in 0..2 {
+            let token = installation.token(&settings, &client).await.unwrap();
+            assert_eq!(token, "ghs_valid");
+        }
+        let requests = github.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("POST /api/v3/app/installations/7/access_tokens "));
+        let jwt = requests[0]
+            .lines()
+            .find_map(|line| line.strip_prefix("authorization: Bearer "))
+            .unwrap();
+        let key = DecodingKey::from_rsa_pem(public_key.as_bytes()).unwrap();
+        let claims = jsonwebtoken::decode::<serde_json::Value>(
+            jwt,
+            &key,
+            &Validation::new(Algorithm::RS256),
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims["iss"], "42");
+
+        // Tokens about to expire are replaced.
+        let mut installation = Installation::default();
+        let settings = self::settings(&github.url, 8, &private_key);
+        installation.token(&settings, &client).await.unwrap();
+        installation.token(&settings, &client).await.unwrap();
+        assert_eq!(github.requests().len(), 3);
+
+        // Unknown installations are not worth retrying.
+        let e = Installation::default()
+            .token(&self::settings(&github.url, 9, &private_key), &client)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            DeployerError::Provider {
+                status: Some(404),
+                ..
+            }
+        ));
+    }
+}
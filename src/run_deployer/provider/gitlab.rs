@@ -0,0 +1,262 @@
+// GitLab's REST API (v4), gitlab.com or self-hosted.
+
+use super::{split_repository, Credentials, SourceProvider};
+use crate::generate_conf::file_struct::{RepositorySettings, TrackSource};
+use crate::run_deployer::{
+    pull::asset::Asset,
+    pull::checks::{Check, CheckSource, CheckState},
+    pull::tags::Tag,
+};
+use reqwest::RequestBuilder;
+use serde_derive::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct CommitResponse {
+    id: String,
+}
+
+/// Element of `GET /projects/:id/repository/tags`.
+#[derive(Debug, Deserialize)]
+struct TagResponse {
+    name: String,
+    commit: CommitResponse,
+}
+
+/// Element of `GET /projects/:id/releases`.
+#[derive(Debug, Deserialize)]
+struct ReleaseResponse {
+    tag_name: String,
+    #[serde(default)]
+    upcoming_release: bool,
+    commit: Option<CommitResponse>,
+    #[serde(default)]
+    assets: ReleaseAssets,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ReleaseAssets {
+    #[serde(default)]
+    links: Vec<ReleaseLink>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseLink {
+    name: String,
+    url: String,
+    direct_asset_url: Option<String>,
+}
+
+/// Element of `GET /projects/:id/repository/commits/:sha/statuses`,
+/// the latest status of every job and external status.
+#[derive(Debug, Deserialize)]
+struct StatusResponse {
+    #[serde(default)]
+    id: u64,
+    name: String,
+    status: String,
+    #[serde(default)]
+    allow_failure: bool,
+}
+
+pub struct GitLab {
+    /// `https://gitlab.example.com`, without a trailing slash.
+    base: String,
+    /// `group/subgroup/project`.
+    path: String,
+    token: String,
+}
+
+impl GitLab {
+    /// The repository is `host/group/project`. The instance is
+    /// reached at `https://host` unless `base_url` is set.
+    pub fn new(settings: &RepositorySettings) -> Result<Self, String> {
+        let (base, path) = split_repository(&settings.repository, settings.base_url.as_deref())?;
+        Ok(GitLab {
+            base,
+            path: path.trim_end_matches(".git").to_owned(),
+            token: settings.token.clone(),
+        })
+    }
+
+    fn api(&self) -> String {
+        format!("{}/api/v4/projects/{}", self.base, encode(&self.path))
+    }
+}
+
+impl SourceProvider for GitLab {
+    fn full_name(&self) -> &str {
+        &self.path
+    }
+
+    fn commit_url(&self, branch: &str) -> String {
+        format!("{}/repository/commits/{}", self.api(), encode(branch))
+    }
+
+    fn commit(&self, body: &str) -> Result<String, String> {
+        serde_json::from_str::<CommitResponse>(body)
+            .map(|commit| commit.id)
+            .map_err(|e| e.to_string())
+    }
+
+    fn tags_url(&self, source: TrackSource) -> String {
+        match source {
+            TrackSource::Tags => format!("{}/repository/tags?per_page=100", self.api()),
+            TrackSource::Releases => format!("{}/releases?per_page=100", self.api()),
+        }
+    }
+
+    fn tags(&self, body: &str, source: TrackSource) -> Result<Vec<Tag>, String> {
+        let tags = match source {
+            TrackSource::Tags => serde_json::from_str::<Vec<TagResponse>>(body)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|t| Tag {
+                    name: t.name,
+                    sha: Some(t.commit.id),
+                    prerelease: false,
+                    assets: Vec::new(),
+                })
+                .collect(),
+            TrackSource::Releases => serde_json::from_str::<Vec<ReleaseResponse>>(body)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .filter(|r| !r.upcoming_release)
+                .map(|r| Tag {
+                    name: r.tag_name,
+                    sha: r.commit.map(|c| c.id),
+                    prerelease: false,
+                    assets: r
+                        .assets
+                        .links
+                        .into_iter()
+                        .map(|link| Asset {
+                            name: link.name,
+                            url: link.direct_asset_url.unwrap_or(link.url),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        Ok(tags)
+    }
+
+    fn checks_url(&self, sha: &str, source: CheckSource) -> Option<String> {
+        match source {
+            CheckSource::CheckRuns => None,
+            CheckSource::Statuses => Some(format!(
+                "{}/repository/commits/{}/statuses?per_page=100",
+                self.api(),
+                sha
+            )),
+        }
+    }
+
+    fn checks(&self, body: &str, _: CheckSource) -> Result<Vec<Check>, String> {
+        let statuses =
+            serde_json::from_str::<Vec<StatusResponse>>(body).map_err(|e| e.to_string())?;
+        Ok(statuses
+            .into_iter()
+            .map(|status| Check {
+                id: status.id,
+                name: status.name,
+                state: match status.status.as_str() {
+                    "success" | "skipped" => CheckState::Success,
+                    "failed" if status.allow_failure => CheckState::Success,
+                    "failed" | "canceled" => CheckState::Failure,
+                    _ => CheckState::Pending,
+                },
+            })
+            .collect())
+    }
+
+    fn clone_url(&self) -> String {
+        format!("{}/{}.git", self.base, self.path)
+    }
+
+    fn credentials(&self) -> Credentials {
+        Credentials {
+            username: "oauth2".to_owned(),
+            password: self.token.clone(),
+            ssh_key: None,
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request.header("PRIVATE-TOKEN", &self.token)
+    }
+}
+
+/// Percent-encode a project path or a ref for use in a URL path.
+fn encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gitlab(repository: &str, base_url: Option<&str>) -> GitLab {
+        GitLab::new(&RepositorySettings {
+            provider: "gitlab".to_owned(),
+            repository: repository.to_owned(),
+            base_url: base_url.map(str::to_owned),
+            token: "glpat-token".to_owned(),
+            ..RepositorySettings::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_urls() {
+        let gitlab = gitlab("gitlab.com/group/sub/project", None);
+        assert_eq!(
+            gitlab.commit_url("feature/x"),
+            "https://gitlab.com/api/v4/projects/group%2Fsub%2Fproject/repository/commits/feature%2Fx"
+        );
+        assert_eq!(
+            gitlab.clone_url(),
+            "https://gitlab.com/group/sub/project.git"
+        );
+
+        let gitlab = gitlab_self_hosted();
+        assert_eq!(
+            gitlab.tags_url(TrackSource::Tags),
+            "https://git.example.com/gitlab/api/v4/projects/team%2Fapi/repository/tags?per_page=100"
+        );
+        assert_eq!(
+            gitlab.clone_url(),
+            "https://git.example.com/gitlab/team/api.git"
+        );
+    }
+
+    fn gitlab_self_hosted() -> GitLab {
+        gitlab(
+            "git.example.com/team/api",
+            Some("https://git.example.com/gitlab/"),
+        )
+    }
+
+    #[test]
+    fn test_responses() {
+        let gitlab = gitlab_self_hosted();
+        assert_eq!(gitlab.commit(r#"{ "id": "0123abc" }"#).unwrap(), "0123abc");
+
+        let body = r#"[
+            { "tag_name": "v2.0.0", "upcoming_release": true },
+            { "tag_name": "v1.0.0", "commit": { "id": "0123abc" },
+              "assets": { "links": [{ "name": "api.tar.gz", "url": "https://example.com/a",
+                                      "direct_asset_url": "https://example.com/d" }] } }
+        ]"#;
+        let tags = gitlab.tags(body, TrackSource::Releases).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].sha.as_deref(), Some("0123abc"));
+        assert_eq!(tags[0].assets[0].url, "https://example.com/d");
+    }
+}
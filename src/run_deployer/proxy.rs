@@ -0,0 +1,220 @@
+// Built-in reverse proxy for blue/green deploys. Forwards every
+// connection to the live release so a new release can be started
+// on the other port, health checked and switched to without
+// dropping requests.
+
+use crate::log;
+use chrono::{DateTime, Local};
+use reqwest::Client;
+use std::{
+    collections::HashMap,
+    io::Result,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    time::{self, Duration, Instant},
+};
+
+pub struct Proxy {
+    addr: SocketAddr,
+    /// Port of the live release, 0 if there is none.
+    active: AtomicU16,
+    connections: Mutex<HashMap<u16, usize>>,
+}
+
+/// Counts an open connection to a backend
+/// until dropped. Used for draining.
+struct Connection<'a> {
+    proxy: &'a Proxy,
+    port: u16,
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.proxy.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.port) {
+            *count -= 1;
+        }
+    }
+}
+
+impl Proxy {
+    /// Listen on `listen` and forward connections to
+    /// `127.0.0.1:active`. Must be called inside of the runtime.
+    pub fn bind(listen: &str, active: Option<u16>) -> Result<Arc<Proxy>> {
+        let listener = std::net::TcpListener::bind(listen)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let proxy = Arc::new(Proxy {
+            addr: listener.local_addr()?,
+            active: AtomicU16::new(active.unwrap_or(0)),
+            connections: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(accept(listener, proxy.clone()));
+        Ok(proxy)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn active(&self) -> Option<u16> {
+        match self.active.load(Ordering::SeqCst) {
+            0 => None,
+            port => Some(port),
+        }
+    }
+
+    /// Send all new connections to `port`.
+    pub fn switch(&self, port: u16) {
+        self.active.store(port, Ordering::SeqCst);
+        log!("Proxy {} now forwards to port {}", self.addr, port);
+    }
+
+    /// Wait until connections to `port` are closed.
+    /// Returns `false` if some are still open after `timeout`.
+    pub async fn drain(&self, port: u16, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let open = self.open_connections(port);
+            if open == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                log!("{} connections to port {} are still open", open, port);
+                return false;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    fn open_connections(&self, port: u16) -> usize {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&port).copied().unwrap_or(0)
+    }
+
+    fn connect(&self) -> Option<Connection<'_>> {
+        let port = self.active()?;
+        *self.connections.lock().unwrap().entry(port).or_insert(0) += 1;
+        Some(Connection { proxy: self, port })
+    }
+}
+
+async fn accept(listener: TcpListener, proxy: Arc<Proxy>) {
+    loop {
+        let (mut client, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log!("Proxy failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            // Connection is counted from here so the backend
+            // is not stopped while we are connecting to it.
+            let connection = match proxy.connect() {
+                Some(connection) => connection,
+                None => return,
+            };
+            match TcpStream::connect(("127.0.0.1", connection.port)).await {
+                Ok(mut backend) => {
+                    let _ = copy_bidirectional(&mut client, &mut backend).await;
+                }
+                Err(e) => {
+                    log!("Proxy failed to connect to port {}: {}", connection.port, e);
+                }
+            }
+        });
+    }
+}
+
+/// Poll `http://127.0.0.1:port{path}` until it answers with 2xx.
+/// Returns `false` if it did not within `timeout`.
+pub async fn health_check(port: u16, path: &str, timeout: Duration) -> bool {
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{}{}", port, path);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let request = client.get(&url).timeout(Duration::from_secs(5)).send();
+        if let Ok(res) = request.await {
+            if res.status().is_success() {
+                return true;
+            }
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Backend that answers every connection with `reply`.
+    async fn backend(reply: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    async fn request(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_switch() {
+        let blue = backend("blue").await;
+        let green = backend("green").await;
+        let proxy = Proxy::bind("127.0.0.1:0", Some(blue)).unwrap();
+
+        assert_eq!(request(proxy.local_addr()).await, "blue");
+        proxy.switch(green);
+        assert_eq!(request(proxy.local_addr()).await, "green");
+        assert!(proxy.drain(blue, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_connections() {
+        let blue = backend("blue").await;
+        let proxy = Proxy::bind("127.0.0.1:0", Some(blue)).unwrap();
+
+        // Open connection that never sends anything.
+        let idle = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!proxy.drain(blue, Duration::from_millis(200)).await);
+
+        drop(idle);
+        assert!(proxy.drain(blue, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let healthy = backend("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let broken = backend("HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n").await;
+
+        assert!(health_check(healthy, "/health", Duration::from_secs(1)).await);
+        assert!(!health_check(broken, "/health", Duration::from_millis(300)).await);
+    }
+}
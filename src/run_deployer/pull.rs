@@ -1,22 +1,48 @@
-use crate::generate_conf::file_struct::{Commit, ConfigFile};
-use build::build;
-use chrono::{prelude::DateTime, Local};
-use git2::Repository;
-use reqwest::{Client, Response};
-use std::error::Error;
-use std::{fmt::Display, path::Path};
-use tokio::time::{self, Duration};
+use super::{
+    control::{process_env, Control},
+    history::DeployResult,
+    provider::{self, git, github_app::Installation, Credentials, GitHub, SourceProvider},
+    proxy::health_check,
+};
+use crate::error::DeployerError;
+use crate::generate_conf::file_struct::{
+    ConfigFile, DirectiveScope, RepositorySettings, Service, SignatureSource,
+};
 use crate::log;
+use asset::{Asset, Release};
+use backoff::{rate_limit_delay, Backoff};
+use build::{build, link_release, move_build, release_dir, slot_name};
+use changes::Watch;
+use checks::Gate;
+use chrono::{prelude::DateTime, Local, Utc};
+use directives::Directives;
+use git2::{
+    build::{CheckoutBuilder, RepoBuilder},
+    FetchOptions, Oid, Repository,
+};
+use reqwest::{
+    header::{ETAG, IF_NONE_MATCH},
+    Client, Response, StatusCode,
+};
+use signature::Verdict;
+use std::error::Error;
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+use tags::Tag;
+use tokio::time::Duration;
 
+pub mod asset;
+mod backoff;
 pub mod build;
-
-/// Local struct. Used to pass
-/// these three fields across functions.
-pub struct RepositoryInfo<'a> {
-    pub url: String,
-    pub author: &'a str,
-    pub name: &'a str,
-}
+pub mod changes;
+pub mod checks;
+pub mod directives;
+pub mod report;
+pub mod signature;
+pub mod tags;
 
 #[derive(Debug)]
 pub enum FolderFormatError {
@@ -36,78 +62,774 @@ impl Display for FolderFormatError {
     }
 }
 
-/// This function makes request to the GitHub's REST API.
+/// This function makes request to the provider's REST API.
 /// Also builds "services" that are specified in the config file.
-pub async fn ping<'a>(
-    config: &ConfigFile,
-    repository: &RepositoryInfo<'a>
-) -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
+/// Polls the repository with ID `id`, every repository
+/// runs its own loop.
+///
+/// Configuration is taken from `control` on every iteration
+/// so `reload` applies without restarting Deployer. Returns
+/// once the repository was removed from the configuration.
+///
+/// Only bad credentials and broken configuration stop it,
+/// failed builds and deploys are logged and retried
+/// with the next commit.
+pub async fn ping(control: &Control, id: &str) -> Result<(), DeployerError> {
+    let client = Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| DeployerError::Provider {
+            url: String::new(),
+            status: None,
+            message: format!("Failed to create HTTP client: {e}"),
+        })?;
+    let mut backoff = Backoff::default();
     let mut last_commit = String::from("");
+    // Branch or tag `last_commit` was taken from.
+    let mut last_ref = String::new();
+    // Assets of the release `last_ref`.
+    let mut last_assets: Vec<Asset> = Vec::new();
+    // ETag of the last response and URL it belongs to.
+    let mut etag: Option<(String, String)> = None;
+    // Head whose required checks failed and the ones that did.
+    // Not seen, so a 304 still finds it, and only reported again
+    // once the failed checks change.
+    let mut failed: Option<(String, Vec<String>)> = None;
+    let mut installation = Installation::default();
     loop {
-        // Make request
-        let res = send_request(&repository.url, &config.token, &client).await?;
-
-        // Panic if an error occurred
-        if !res.status().is_success() {
-            let msg: String = format!("Failed to fetch data: {}", res.status());
-            if res.status() == 401 {
-                panic!("{}", msg);
+        let config = control.config();
+        let settings = match config.repository(id) {
+            Some(settings) => settings,
+            None => {
+                log!("Stopped watching {}", id);
+                return Ok(());
             }
+        };
+        let config_error = |message| DeployerError::Config {
+            path: control.path().to_owned(),
+            message,
+        };
+        let delay = poll_delay(settings.poll_interval, settings.poll_jitter);
+        let force = control.take_force(id);
+        if control.is_paused() && !force {
+            control.wait(id, delay).await;
             continue;
         }
+        // GitHub Apps poll and clone with the installation token.
+        let with_app_token;
+        let settings = match &settings.github_app {
+            Some(_) => match installation.token(settings, &client).await {
+                Ok(token) => {
+                    with_app_token = RepositorySettings {
+                        token,
+                        ..settings.clone()
+                    };
+                    &with_app_token
+                }
+                // A wrong app or installation, just like a bad token.
+                Err(
+                    e @ DeployerError::Provider {
+                        status: Some(401 | 403 | 404),
+                        ..
+                    },
+                ) => return Err(e),
+                Err(e) => {
+                    let delay = backoff.next();
+                    retry_later(control, id, e.to_string(), delay).await;
+                    continue;
+                }
+            },
+            None => settings,
+        };
+        let provider = provider::new(settings).map_err(config_error)?;
+        let url =
+            provider::latest_url(provider.as_ref(), &settings.branch, settings.track.as_ref())
+                .map_err(config_error)?;
+
+        let (sha, limit) = match provider.refs() {
+            // Providers without an API list the refs of the remote.
+            Some(refs) => {
+                let listed = match tokio::task::spawn_blocking(move || refs.list()).await {
+                    Ok(listed) => listed.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                let target = listed
+                    .and_then(|refs| git::latest(&refs, &settings.branch, settings.track.as_ref()));
+                let tag = match target {
+                    Ok(Some(tag)) => tag,
+                    Ok(None) => {
+                        let track = settings.track.as_ref().unwrap();
+                        control.record_poll(id, None, Some(format!("No tag matches {}", track)));
+                        control.wait(id, delay).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        let delay = backoff.next();
+                        let msg = format!("Failed to list refs of {url}: {e}");
+                        retry_later(control, id, msg, delay).await;
+                        continue;
+                    }
+                };
+                last_ref = tag.name;
+                (tag.sha.unwrap_or_default(), None)
+            }
+            None => {
+                // Make request. Unchanged branch comes back as
+                // 304 which does not count against the rate limit.
+                let if_none_match = match &etag {
+                    Some((etag_url, tag)) if *etag_url == url => Some(tag.as_str()),
+                    _ => None,
+                };
+                let res = match send_request(provider.as_ref(), &url, if_none_match, &client).await
+                {
+                    Ok(res) => res,
+                    Err(e) => {
+                        // Network hiccups are not worth dying for.
+                        let delay = backoff.next();
+                        retry_later(control, id, format!("Failed to reach {url}: {e}"), delay)
+                            .await;
+                        continue;
+                    }
+                };
+                let limit = rate_limit_delay(res.headers(), Utc::now().timestamp());
+
+                let sha = if res.status() == StatusCode::NOT_MODIFIED {
+                    match &failed {
+                        Some((sha, _)) => sha.clone(),
+                        None => last_commit.clone(),
+                    }
+                } else {
+                    // Bad credentials stop polling, anything else is retried.
+                    if !res.status().is_success() {
+                        let msg: String = format!("Failed to fetch data: {}", res.status());
+                        if res.status() == 401 {
+                            return Err(DeployerError::Provider {
+                                url: url.clone(),
+                                status: Some(401),
+                                message: "Bad credentials, check your token".to_owned(),
+                            });
+                        }
+                        // 403/429 from the rate limiter tell us how long to wait,
+                        // anything else (5xx mostly) is retried with backoff.
+                        let delay = limit.unwrap_or_else(|| backoff.next());
+                        retry_later(control, id, msg, delay).await;
+                        continue;
+                    }
 
-        let body = res.text().await?;
-        let response: Commit = serde_json::from_str(&body)?;
+                    let new_etag = res
+                        .headers()
+                        .get(ETAG)
+                        .and_then(|v| v.to_str().ok())
+                        .map(|tag| (url.clone(), tag.to_owned()));
+                    let body = match res.text().await {
+                        Ok(body) => body,
+                        Err(e) => {
+                            let delay = backoff.next();
+                            let msg = format!("Failed to read response: {e}");
+                            retry_later(control, id, msg, delay).await;
+                            continue;
+                        }
+                    };
+                    let target = match &settings.track {
+                        Some(track) => provider
+                            .tags(&body, track.source)
+                            .and_then(|tags| tags::newest(track, tags)),
+                        None => provider.commit(&body).map(|sha| {
+                            Some(Tag {
+                                name: settings.branch.clone(),
+                                sha: Some(sha),
+                                prerelease: false,
+                                assets: Vec::new(),
+                            })
+                        }),
+                    }
+                    .map_err(|e| DeployerError::Provider {
+                        url: url.clone(),
+                        status: Some(200),
+                        message: format!("Unexpected response: {e}"),
+                    })?;
+                    let tag = match target {
+                        Some(tag) => tag,
+                        None => {
+                            etag = new_etag;
+                            let track = settings.track.as_ref().unwrap();
+                            control.record_poll(
+                                id,
+                                None,
+                                Some(format!("No tag matches {}", track)),
+                            );
+                            control.wait(id, delay).await;
+                            continue;
+                        }
+                    };
+                    let sha = match tag.sha {
+                        Some(sha) => sha,
+                        // Releases may only name their tag.
+                        None => match tag_commit(provider.as_ref(), &tag.name, &client).await {
+                            Ok(sha) => sha,
+                            Err(e) => {
+                                let delay = backoff.next();
+                                retry_later(control, id, e, delay).await;
+                                continue;
+                            }
+                        },
+                    };
+                    etag = new_etag;
+                    last_ref = tag.name;
+                    last_assets = tag.assets;
+                    sha
+                };
+                (sha, limit)
+            }
+        };
+        backoff.reset();
+        control.record_poll(id, Some(&sha), None);
 
         // Check for new commits
-        if last_commit != response.sha {
-            last_commit = response.sha.clone();
-            let url = format!(
-                "https://github.com/{}/{}.git",
-                repository.author, repository.name
-            );
-            let pull_dir = format!("{}/{}", config.pull_dir, get_time());
-            let pull_path = pull_repository(&url, &pull_dir)?;
+        if !sha.is_empty() && (force || last_commit != sha) {
+            // Forced deploys don't wait for CI.
+            if !force && !settings.required_checks.is_empty() {
+                let gate = checks::fetch(provider.as_ref(), &sha, &client)
+                    .await
+                    .map(|found| checks::gate(&settings.required_checks, &found));
+                match gate {
+                    Ok(Gate::Pass) => {}
+                    Ok(Gate::Pending(names)) => {
+                        // Polled in full again, a 304 would hide the commit.
+                        etag = None;
+                        failed = None;
+                        let msg = format!("{} waits for {}", sha, names.join(", "));
+                        control.record_poll(id, Some(&sha), Some(msg));
+                        control.wait(id, delay).await;
+                        continue;
+                    }
+                    Ok(Gate::Fail(names)) => {
+                        // Not marked as seen either, failed jobs may be re-run.
+                        let msg = format!("{} failed {}", sha, names.join(", "));
+                        let failure = (sha, names);
+                        if failed.as_ref() == Some(&failure) {
+                            control.record_poll(id, None, Some(msg));
+                            control.wait(id, delay).await;
+                        } else {
+                            skip_commit(control, id, &failure.0, msg, delay).await;
+                            failed = Some(failure);
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        etag = None;
+                        let delay = backoff.next();
+                        retry_later(control, id, e, delay).await;
+                        continue;
+                    }
+                }
+            }
+            failed = None;
+            let signatures = settings.signatures.as_ref();
+            if signatures.is_some_and(|s| s.source == SignatureSource::Github) {
+                let verdict = match GitHub::new(settings) {
+                    Ok(github) => signature::verify_github(&github, &sha, &client).await,
+                    Err(e) => Err(e),
+                };
+                match verdict {
+                    Ok(Verdict::Trusted(_)) => {}
+                    Ok(Verdict::Refused(msg)) => {
+                        skip_commit(control, id, &sha, msg, delay).await;
+                        last_commit = sha;
+                        continue;
+                    }
+                    Err(e) => {
+                        etag = None;
+                        let delay = backoff.next();
+                        retry_later(control, id, e, delay).await;
+                        continue;
+                    }
+                }
+            }
+            let url = provider.clone_url();
+            let credentials = provider.credentials();
+            if settings.track.is_some() {
+                log!("{}: deploying {} ({})", id, last_ref, sha);
+            }
+            let pull_dir = format!("{}/{}", settings.pull_dir, get_time());
+            let (commit, reference) = (sha.clone(), last_ref.clone());
+            let pull_path = match tokio::task::spawn_blocking(move || {
+                pull_repository(&url, &pull_dir, &commit, &reference, &credentials)
+            })
+            .await
+            {
+                Ok(Ok(pull_path)) => pull_path,
+                Ok(Err(e)) => {
+                    // Commit is not marked as seen, so it is retried.
+                    // Polled in full again, a 304 would hide the commit.
+                    etag = None;
+                    let delay = backoff.next();
+                    retry_later(control, id, e.to_string(), delay).await;
+                    continue;
+                }
+                Err(e) => {
+                    etag = None;
+                    let delay = backoff.next();
+                    retry_later(control, id, format!("Clone failed: {e}"), delay).await;
+                    continue;
+                }
+            };
             let path = Path::new(&pull_path);
+            if let Some(signatures) = signatures.filter(|s| s.source == SignatureSource::Local) {
+                let (clone, commit, signatures) =
+                    (path.to_path_buf(), sha.clone(), signatures.clone());
+                let verdict = tokio::task::spawn_blocking(move || {
+                    signature::verify_local(&clone, &commit, &signatures)
+                })
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+                match verdict {
+                    Ok(Verdict::Trusted(signer)) => {
+                        log!("{}: {} is signed by {}", id, sha, signer);
+                    }
+                    Ok(Verdict::Refused(msg)) => {
+                        let _ = std::fs::remove_dir_all(path);
+                        skip_commit(control, id, &sha, msg, delay).await;
+                        last_commit = sha;
+                        continue;
+                    }
+                    Err(e) => {
+                        let _ = std::fs::remove_dir_all(path);
+                        etag = None;
+                        let delay = backoff.next();
+                        retry_later(control, id, e, delay).await;
+                        continue;
+                    }
+                }
+            }
+            // Not from `last_commit`, a skipped commit was seen
+            // but its directives still count.
+            let since = match settings.directives {
+                DirectiveScope::SinceLastDeploy => {
+                    control.last_deployed(settings).map(|deploy| deploy.commit)
+                }
+                _ => None,
+            };
+            let directives = match settings.directives {
+                DirectiveScope::Off => Ok(Directives::default()),
+                _ => {
+                    let (clone, commit) = (path.to_path_buf(), sha.clone());
+                    tokio::task::spawn_blocking(move || {
+                        directives::read(&clone, &commit, since.as_deref())
+                    })
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|directives| directives.map_err(|e| e.to_string()))
+                }
+            };
+            let directives = match directives {
+                Ok(directives) => directives,
+                Err(e) => {
+                    let _ = std::fs::remove_dir_all(path);
+                    etag = None;
+                    let delay = backoff.next();
+                    retry_later(control, id, e, delay).await;
+                    continue;
+                }
+            };
+            // Forced deploys ignore `[skip deploy]` only.
+            if directives.skip() && !force {
+                let _ = std::fs::remove_dir_all(path);
+                let msg = "skipped by [skip deploy]".to_owned();
+                skip_commit(control, id, &sha, msg, delay).await;
+                last_commit = sha;
+                continue;
+            }
+            for name in directives.services.iter().flatten() {
+                if !settings.services.iter().any(|s| directives::names(name, s)) {
+                    log!("{}: [deploy: {}] names no service", id, name);
+                }
+            }
+            last_commit = sha;
 
-            // todo: change hardcoded.
-            //  Put into for s in services
-            let build_dir = Path::new(&config.services[0].build_dir);
-            build(path, &build_dir, config.services[0].name.to_owned())?;
+            let release = Release {
+                tag: &last_ref,
+                assets: &last_assets,
+                provider: provider.as_ref(),
+            };
+            for service in &settings.services {
+                if !directives.includes(service) {
+                    log!("{}: {} is not in [deploy: ...]", id, service.name);
+                    continue;
+                }
+                // Forced deploys and `[clean build]` build services
+                // whose files didn't change too.
+                if !force && !directives.clean {
+                    if let Some(base) =
+                        unchanged_since(control, settings, service, path, &last_commit).await
+                    {
+                        log!("{}: {} is unchanged since {}", id, service.name, base);
+                        control.record_deploy(
+                            settings,
+                            &service.name,
+                            &last_commit,
+                            DeployResult::Unchanged,
+                        );
+                        continue;
+                    }
+                }
+                let root = service_root(&settings.pull_dir, &service.root_dir, path);
+                let assets = path.join(".deployer-assets").join(&service.name);
+                let deployment = match &settings.report {
+                    Some(report) => {
+                        report::start(report, settings, service.base_name(), &last_commit, &client)
+                            .await
+                    }
+                    None => None,
+                };
+                let output = match &service.asset {
+                    Some(asset) => {
+                        asset::fetch(asset, &release, &assets)
+                            .await
+                            .map_err(|message| DeployerError::Build {
+                                service: service.name.clone(),
+                                message,
+                            })
+                    }
+                    None => build_service(control, service, &root, directives.clean).await,
+                };
+                let result = match output {
+                    Ok(output) => deploy(control, &config, service, &output).await,
+                    Err(e) => Err(e),
+                };
+                if let Some(deployment) = &deployment {
+                    deployment.finish(result.is_ok(), &client).await;
+                }
+                let deployed = if result.is_ok() {
+                    DeployResult::Deployed
+                } else {
+                    DeployResult::Failed
+                };
+                control.record_deploy(settings, &service.name, &last_commit, deployed);
+                if let Err(e) = result {
+                    log!("{}", e);
+                }
+            }
+        }
+        if let Some(limit) = limit.filter(|limit| *limit > delay) {
+            log!("Rate limit exhausted, next poll in {}s", limit.as_secs());
+            control.wait(id, limit).await;
+        } else {
+            control.wait(id, delay).await;
+        }
+    }
+}
+
+async fn retry_later(control: &Control, id: &str, msg: String, delay: Duration) {
+    log!("{}: {}, retrying in {}s", id, msg, delay.as_secs());
+    control.record_poll(id, None, Some(msg));
+    control.wait(id, delay).await;
+}
+
+/// Don't deploy `sha` and say why. Retried on the next poll
+/// unless the caller marks it as seen.
+async fn skip_commit(control: &Control, id: &str, sha: &str, msg: String, delay: Duration) {
+    log!("{}: not deploying {}: {}", id, sha, msg);
+    control.record_poll(id, Some(sha), Some(msg));
+    control.wait(id, delay).await;
+}
+
+/// Commit `service` was last deployed from, if none of its
+/// files changed between it and `sha` in the clone at `path`.
+/// Services deployed from assets always count as changed.
+async fn unchanged_since(
+    control: &Control,
+    settings: &RepositorySettings,
+    service: &Service,
+    path: &Path,
+    sha: &str,
+) -> Option<String> {
+    if service.asset.is_some() {
+        return None;
+    }
+    let base = control
+        .last_deploy(&service.name)
+        .filter(|deploy| deploy.result != DeployResult::Failed)?
+        .commit;
+    let dir = service_path(&settings.pull_dir, &service.root_dir);
+    let watch = match Watch::new(dir, &service.watch_paths) {
+        Ok(watch) => watch,
+        Err(e) => {
+            log!("{}: {}", service.name, e);
+            return None;
+        }
+    };
+    let (clone, from, to) = (path.to_path_buf(), base.clone(), sha.to_owned());
+    let changed = tokio::task::spawn_blocking(move || changes::changed(&clone, &from, &to, &watch))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|changed| changed.map_err(|e| e.to_string()));
+    match changed {
+        Ok(false) => Some(base),
+        Ok(true) => None,
+        Err(e) => {
+            log!(
+                "{}: failed to compare {} to {}: {}",
+                service.name,
+                base,
+                sha,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Poll interval plus random jitter, so several Deployers
+/// started together do not hit the API at the same moment.
+fn poll_delay(interval: u64, jitter: u64) -> Duration {
+    Duration::from_secs(interval) + Duration::from_millis(fastrand::u64(0..=jitter * 1000))
+}
+
+/// Build the service in `root`, from scratch if `clean`.
+/// Builds of all repositories share a queue, one at a time.
+async fn build_service(
+    control: &Control,
+    service: &Service,
+    root: &Path,
+    clean: bool,
+) -> Result<PathBuf, DeployerError> {
+    let build_error = |message: String| DeployerError::Build {
+        service: service.name.clone(),
+        message,
+    };
+    let _turn = control.build_turn().await;
+    let root = root.to_path_buf();
+    tokio::task::spawn_blocking(move || build(&root, clean))
+        .await
+        .map_err(|e| build_error(e.to_string()))?
+        .map_err(|e| build_error(e.to_string()))
+}
+
+/// Replace the current release of a service with `output`.
+/// Supervised services are stopped right before
+/// the swap and started again from the new release.
+async fn deploy(
+    control: &Control,
+    config: &ConfigFile,
+    service: &Service,
+    output: &Path,
+) -> Result<(), DeployerError> {
+    if service.proxy.is_some() {
+        return deploy_blue_green(control, config, service, output).await;
+    }
+    let build_dir = Path::new(&service.build_dir);
+    let supervised = config.supervisor.is_some() && service.command.is_some();
+    if supervised {
+        control.supervisor.stop(&service.name).await;
+    }
+    move_build(output, build_dir, &service.name).map_err(|e| deploy_error(service, e))?;
+    if supervised {
+        control
+            .start_service(&service.name)
+            .map_err(|e| deploy_error(service, e))?;
+    }
+    Ok(())
+}
+
+/// Start the new release next to the live one, switch the
+/// proxy once it is healthy, then drain and stop the old one.
+/// The live release keeps serving if the new one is unhealthy.
+async fn deploy_blue_green(
+    control: &Control,
+    config: &ConfigFile,
+    service: &Service,
+    output: &Path,
+) -> Result<(), DeployerError> {
+    let settings = match &config.supervisor {
+        Some(settings) if service.command.is_some() => settings,
+        _ => {
+            return Err(deploy_error(
+                service,
+                "blue/green deploys need the supervisor and a command",
+            ))
         }
-        time::sleep(Duration::from_secs(60)).await;
+    };
+    let proxy_settings = service.proxy.as_ref().unwrap();
+    let proxy = control
+        .proxy(service)
+        .map_err(|e| deploy_error(service, e))?
+        .unwrap();
+    let build_dir = Path::new(&service.build_dir);
+
+    let live = proxy.active();
+    let [blue, green] = proxy_settings.ports;
+    let next = if live == Some(blue) { green } else { blue };
+    let slot = slot_name(&service.name, next);
+
+    // Leftover of a deploy that failed its health check.
+    control.supervisor.stop(&slot).await;
+    move_build(output, build_dir, &slot).map_err(|e| deploy_error(service, e))?;
+    let dir = release_dir(build_dir, &slot);
+    control
+        .supervisor
+        .start(
+            &slot,
+            service,
+            &dir,
+            settings,
+            process_env(service, Some(next)),
+        )
+        .map_err(|e| deploy_error(service, e))?;
+
+    let timeout = Duration::from_secs(proxy_settings.health_timeout);
+    if !health_check(next, &proxy_settings.health_check, timeout).await {
+        control.supervisor.stop(&slot).await;
+        return Err(deploy_error(service, "new release failed its health check"));
+    }
+    proxy.switch(next);
+    link_release(build_dir, &service.name, &slot).map_err(|e| deploy_error(service, e))?;
+
+    if let Some(old) = live {
+        proxy
+            .drain(old, Duration::from_secs(proxy_settings.drain_timeout))
+            .await;
+        control
+            .supervisor
+            .stop(&slot_name(&service.name, old))
+            .await;
+    }
+    Ok(())
+}
+
+fn deploy_error(service: &Service, message: impl Display) -> DeployerError {
+    DeployerError::Deploy {
+        service: service.name.clone(),
+        message: message.to_string(),
+    }
+}
+
+/// Find service's `root_dir` inside a fresh clone.
+///
+/// `root_dir` points into a checkout inside `pull_dir`
+/// (`pull_dir/<checkout>/path/in/repository`), so the
+/// checkout folder is replaced with `clone`. Paths outside
+/// of `pull_dir` fall back to the root of the clone.
+fn service_root(pull_dir: &str, root_dir: &str, clone: &Path) -> PathBuf {
+    clone.join(service_path(pull_dir, root_dir))
+}
+
+/// Service's `root_dir` relative to the repository,
+/// empty for all of it. See `service_root`.
+fn service_path(pull_dir: &str, root_dir: &str) -> PathBuf {
+    match Path::new(root_dir).strip_prefix(pull_dir) {
+        Ok(relative) => relative.components().skip(1).collect(),
+        Err(_) => PathBuf::new(),
     }
 }
 
-async fn send_request(url: &str, token: &str, client: &Client) -> Result<Response, reqwest::Error> {
-    let fmt_token = format!("token {}", token);
-    let response = client
-        .get(url)
-        .header("Authorization", fmt_token)
-        .header("User-Agent", "request")
-        .send()
-        .await?;
+async fn send_request(
+    provider: &dyn SourceProvider,
+    url: &str,
+    etag: Option<&str>,
+    client: &Client,
+) -> Result<Response, reqwest::Error> {
+    let mut request = provider.authorize(client.get(url));
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await?;
     Ok(response)
 }
 
-fn pull_repository(url: &str, root_dir: &str) -> Result<String, Box<dyn Error>> {
+/// Commit the tag `name` points to.
+async fn tag_commit(
+    provider: &dyn SourceProvider,
+    name: &str,
+    client: &Client,
+) -> Result<String, String> {
+    let url = provider.tag_url(name);
+    let res = send_request(provider, &url, None, client)
+        .await
+        .map_err(|e| format!("Failed to reach {url}: {e}"))?;
+    if !res.status().is_success() {
+        return Err(format!("Failed to fetch tag {}: {}", name, res.status()));
+    }
+    let body = res
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read response: {e}"))?;
+    provider
+        .tag_commit(&body)
+        .map_err(|e| format!("Unexpected response: {e}"))
+}
+
+/// Clone `url` into `root_dir` (or next to it, if taken)
+/// and check out `sha`, which was found on `reference`.
+/// `credentials` are used if the remote asks for them.
+fn pull_repository(
+    url: &str,
+    root_dir: &str,
+    sha: &str,
+    reference: &str,
+    credentials: &Credentials,
+) -> Result<String, DeployerError> {
+    let git_error = |source| DeployerError::Git {
+        url: url.to_owned(),
+        source,
+    };
+    let clone = |dest: &str| {
+        RepoBuilder::new()
+            .fetch_options(fetch_options(credentials))
+            .clone(url, Path::new(dest))
+    };
     // Pull repository
-    match Repository::clone(url, root_dir) {
-        Ok(_) => {
+    let (repository, dest) = match clone(root_dir) {
+        Ok(repository) => {
             log!("Fetched from remote branch to {}", root_dir);
-            Ok(root_dir.to_string())
+            (repository, root_dir.to_string())
         }
         Err(e) => match e.code() {
             git2::ErrorCode::Exists => {
-                let new_dest = update_destination(true, root_dir.to_owned(), 1)?;
+                let new_dest = update_destination(true, root_dir.to_owned(), 1).map_err(|e| {
+                    DeployerError::Io {
+                        path: root_dir.to_owned(),
+                        source: io::Error::other(e),
+                    }
+                })?;
                 log!("updated destination: {}", new_dest);
-                Repository::clone(url, &new_dest)?;
-                Ok(new_dest)
+                let repository = clone(&new_dest).map_err(git_error)?;
+                (repository, new_dest)
             }
-            _ => Err(Box::new(e)),
+            _ => return Err(git_error(e)),
         },
+    };
+    checkout(&repository, sha, reference, credentials).map_err(git_error)?;
+    Ok(dest)
+}
+
+/// Answer authentication with `credentials`.
+fn fetch_options(credentials: &Credentials) -> FetchOptions<'_> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(credentials.callbacks());
+    options
+}
+
+/// Detach HEAD at `sha`. Commits only reachable from a
+/// tag are not part of the clone and are fetched first.
+fn checkout(
+    repository: &Repository,
+    sha: &str,
+    reference: &str,
+    credentials: &Credentials,
+) -> Result<(), git2::Error> {
+    let oid = Oid::from_str(sha)?;
+    if repository.find_commit(oid).is_err() {
+        let refspec = format!("+refs/tags/{0}:refs/tags/{0}", reference);
+        repository.find_remote("origin")?.fetch(
+            &[refspec.as_str()],
+            Some(&mut fetch_options(credentials)),
+            None,
+        )?;
     }
+    repository.set_head_detached(oid)?;
+    repository.checkout_head(Some(CheckoutBuilder::new().force()))
 }
 
 /// Recursive function. Checks if directory already exists
@@ -157,7 +879,7 @@ fn destination_fmt(
         "{}_{}_{}_{}",
         base_path[0], base_path[1], base_path[2], base_path[3]
     );
-    path.push_str("_");
+    path.push('_');
     if index < 10 {
         path.push('0');
     }
@@ -173,6 +895,189 @@ fn get_time() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::run_deployer::stand_in::StandIn;
+
+    #[test]
+    fn test_poll_delay() {
+        assert_eq!(poll_delay(60, 0), Duration::from_secs(60));
+        for _ in 0..100 {
+            let delay = poll_delay(60, 10);
+            assert!(delay >= Duration::from_secs(60) && delay <= Duration::from_secs(70));
+        }
+    }
+
+    #[test]
+    fn test_service_root() {
+        let clone = Path::new("/var/www/01_Sep_2024_1308");
+        let root = service_root("/var/www", "/var/www/my_repository/backend/api", clone);
+        assert_eq!(root, Path::new("/var/www/01_Sep_2024_1308/backend/api"));
+
+        let root = service_root("/var/www", "/srv/elsewhere", clone);
+        assert_eq!(root, clone);
+        assert_eq!(
+            service_path("/var/www", "/var/www/my_repository/backend/api"),
+            Path::new("backend/api")
+        );
+    }
+
+    #[test]
+    fn test_pull_checks_out_commit() {
+        let dir = std::env::temp_dir().join(format!("deployer-pull-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let origin = Repository::init(dir.join("origin")).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let commit = |content: &str| {
+            std::fs::write(dir.join("origin/VERSION"), content).unwrap();
+            let mut index = origin.index().unwrap();
+            index.add_path(Path::new("VERSION")).unwrap();
+            let tree = origin.find_tree(index.write_tree().unwrap()).unwrap();
+            let parents: Vec<_> = origin
+                .head()
+                .ok()
+                .map(|h| h.peel_to_commit().unwrap())
+                .into_iter()
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+            origin
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    content,
+                    &tree,
+                    &parents,
+                )
+                .unwrap()
+        };
+        let first = commit("1.0.0");
+        commit("2.0.0");
+
+        let url = dir.join("origin").to_string_lossy().into_owned();
+        let clone = dir.join("clone").to_string_lossy().into_owned();
+        let credentials = Credentials::default();
+        let path =
+            pull_repository(&url, &clone, &first.to_string(), "v1.0.0", &credentials).unwrap();
+        let version = std::fs::read_to_string(Path::new(&path).join("VERSION")).unwrap();
+        assert_eq!(version, "1.0.0");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_clone_is_retried_after_304() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let github = StandIn::start(vec![(
+            "/api/v3/repos/a/b/commits/main",
+            200,
+            format!(r#"{{ "sha": "{}" }}"#, sha).into_bytes(),
+        )])
+        .await;
+        let dir = std::env::temp_dir().join(format!("deployer-retry-{}", std::process::id()));
+        let mut config = ConfigFile {
+            repository: "ghe.corp/a/b".to_owned(),
+            base_url: Some(github.url.clone()),
+            token: "token".to_owned(),
+            pull_dir: dir.to_string_lossy().into_owned(),
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        let id = config.repositories[0].id();
+        let control = std::sync::Arc::new(Control::new("", config));
+        let polling = {
+            let (control, id) = (control.clone(), id.clone());
+            tokio::spawn(async move { ping(&control, &id).await })
+        };
+        // The clone fails as the stand-in serves no repository.
+        let clones = || {
+            github
+                .requests()
+                .iter()
+                .filter(|request| request.contains("/a/b.git/"))
+                .count()
+        };
+        for attempt in 1..=2 {
+            for _ in 0..100 {
+                if clones() >= attempt {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            assert_eq!(clones(), attempt);
+            control.poll_now(&id);
+        }
+        polling.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_failed_checks_keep_polls_conditional() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let github = StandIn::start(vec![
+            (
+                "/api/v3/repos/a/b/commits/main",
+                200,
+                format!(r#"{{ "sha": "{}" }}"#, sha).into_bytes(),
+            ),
+            (
+                "/api/v3/repos/a/b/commits/0123456789abcdef0123456789abcdef01234567/check-runs?per_page=100",
+                200,
+                br#"{ "check_runs": [
+                    { "id": 1, "name": "build", "status": "completed", "conclusion": "failure" }
+                ] }"#
+                    .to_vec(),
+            ),
+            (
+                "/api/v3/repos/a/b/commits/0123456789abcdef0123456789abcdef01234567/status?per_page=100",
+                200,
+                br#"{ "statuses": [] }"#.to_vec(),
+            ),
+        ])
+        .await;
+        let dir = std::env::temp_dir().join(format!("deployer-gate-{}", std::process::id()));
+        let mut config = ConfigFile {
+            repository: "ghe.corp/a/b".to_owned(),
+            base_url: Some(github.url.clone()),
+            token: "token".to_owned(),
+            pull_dir: dir.to_string_lossy().into_owned(),
+            required_checks: vec!["build".to_owned()],
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        let id = config.repositories[0].id();
+        let control = std::sync::Arc::new(Control::new("", config));
+        let polling = {
+            let (control, id) = (control.clone(), id.clone());
+            tokio::spawn(async move { ping(&control, &id).await })
+        };
+        let requests = |path: &str| {
+            github
+                .requests()
+                .into_iter()
+                .filter(|request| request.contains(path))
+                .collect::<Vec<_>>()
+        };
+        for poll in 1..=3 {
+            for _ in 0..100 {
+                if requests("/check-runs").len() >= poll {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            assert_eq!(requests("/check-runs").len(), poll);
+            control.poll_now(&id);
+        }
+        polling.abort();
+
+        // Checks are looked at again, the head is only asked for if changed.
+        let heads = requests("/commits/main ");
+        assert!(heads[1..]
+            .iter()
+            .all(|request| request.contains("if-none-match: \"stand-in\"")));
+        assert!(requests("/a/b.git/").is_empty());
+        let status = control.status();
+        let error = status.repositories[0].last_error.as_deref().unwrap();
+        assert_eq!(error, format!("{} failed build", sha));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
     #[test]
     fn test_non_existent_path() {
@@ -0,0 +1,212 @@
+// Prebuilt release assets. The asset of a service is downloaded
+// from the deployed GitHub Release, checked against the checksum
+// file published with it and unpacked into a new directory that
+// replaces the current release just like a build output does.
+
+use crate::generate_conf::file_struct::AssetSettings;
+use crate::run_deployer::provider::SourceProvider;
+use flate2::read::GzDecoder;
+use reqwest::{header::ACCEPT, Client};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, Permissions},
+    io::{self, Cursor},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// Element of `assets` of a release.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    /// API URL of the asset. Answers with the file
+    /// itself to `Accept: application/octet-stream`.
+    pub url: String,
+}
+
+/// Release the asset of a service is taken from.
+pub struct Release<'a> {
+    pub tag: &'a str,
+    pub assets: &'a [Asset],
+    pub provider: &'a dyn SourceProvider,
+}
+
+/// Download the asset named in `settings`, verify its
+/// checksum and unpack it into `dir`, which is returned.
+pub async fn fetch(
+    settings: &AssetSettings,
+    release: &Release<'_>,
+    dir: &Path,
+) -> Result<PathBuf, String> {
+    let name = expand(&settings.name, release.tag);
+    let checksums = expand(&settings.checksums, release.tag);
+    let client = Client::new();
+    let data = download(&client, release, &name).await?;
+    let sums = download(&client, release, &checksums).await?;
+
+    let sums = String::from_utf8_lossy(&sums);
+    let expected = checksum(&sums, &name)
+        .ok_or_else(|| format!("{} has no checksum of {}", checksums, name))?;
+    let actual = hex::encode(Sha256::digest(&data));
+    if !expected.eq_ignore_ascii_case(&actual) {
+        return Err(format!(
+            "checksum of {} does not match: expected {}, got {}",
+            name, expected, actual
+        ));
+    }
+
+    let dir = dir.to_path_buf();
+    let file = name.clone();
+    tokio::task::spawn_blocking(move || unpack(&file, &data, &dir).map(|_| dir))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Failed to unpack {}: {}", name, e))
+}
+
+/// Replace `{tag}` and `{version}` (`tag` without a leading `v`).
+fn expand(template: &str, tag: &str) -> String {
+    template
+        .replace("{tag}", tag)
+        .replace("{version}", tag.trim_start_matches(['v', 'V']))
+}
+
+async fn download(client: &Client, release: &Release<'_>, name: &str) -> Result<Vec<u8>, String> {
+    let asset = release
+        .assets
+        .iter()
+        .find(|asset| asset.name == name)
+        .ok_or_else(|| format!("release {} has no asset {}", release.tag, name))?;
+    let res = release
+        .provider
+        .authorize(client.get(&asset.url))
+        .header(ACCEPT, "application/octet-stream")
+        .send()
+        .await
+        .map_err(|e| format!("Failed to download {}: {}", name, e))?;
+    if !res.status().is_success() {
+        return Err(format!("Failed to download {}: {}", name, res.status()));
+    }
+    let data = res
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to download {}: {}", name, e))?;
+    Ok(data.to_vec())
+}
+
+/// Checksum of `name` in `sha256sum` output. A file
+/// with nothing but a checksum is taken as is.
+fn checksum<'a>(sums: &'a str, name: &str) -> Option<&'a str> {
+    let mut words = sums.split_whitespace();
+    if let (Some(sum), None) = (words.next(), words.next()) {
+        return Some(sum);
+    }
+    sums.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            // `*` marks files checksummed in binary mode.
+            (Some(sum), Some(file)) if file.trim_start_matches('*') == name => Some(sum),
+            _ => None,
+        }
+    })
+}
+
+fn unpack(name: &str, data: &[u8], dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        tar::Archive::new(GzDecoder::new(data)).unpack(dir)
+    } else if name.ends_with(".tar") {
+        tar::Archive::new(data).unpack(dir)
+    } else if name.ends_with(".zip") {
+        zip::ZipArchive::new(Cursor::new(data))
+            .and_then(|mut archive| archive.extract(dir))
+            .map_err(io::Error::other)
+    } else {
+        let path = dir.join(name);
+        fs::write(&path, data)?;
+        fs::set_permissions(&path, Permissions::from_mode(0o755))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_conf::file_struct::RepositorySettings;
+    use crate::run_deployer::{provider::GitHub, stand_in::StandIn};
+    use flate2::{write::GzEncoder, Compression};
+
+    fn tarball(file: &str, content: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, file, content).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_checksum() {
+        let sums = "0123  api-1.2.0.tar.gz\nabcd *web-1.2.0.zip\n";
+        assert_eq!(checksum(sums, "api-1.2.0.tar.gz"), Some("0123"));
+        assert_eq!(checksum(sums, "web-1.2.0.zip"), Some("abcd"));
+        assert_eq!(checksum(sums, "worker"), None);
+        assert_eq!(checksum("0123\n", "anything"), Some("0123"));
+        assert_eq!(expand("api-{version}.tar.gz", "v1.2.0"), "api-1.2.0.tar.gz");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_verifies_and_unpacks() {
+        let archive = tarball("api", b"#!/bin/sh\n");
+        let sums = format!(
+            "{}  api-1.2.0.tar.gz\n",
+            hex::encode(Sha256::digest(&archive))
+        );
+        let github = StandIn::start(vec![
+            ("/assets/1", 200, archive),
+            ("/assets/2", 200, sums.into_bytes()),
+            ("/assets/3", 200, b"0000  api-1.2.0.tar.gz\n".to_vec()),
+        ])
+        .await;
+        let asset = |name: &str, id: u32| Asset {
+            name: name.to_owned(),
+            url: format!("{}/assets/{}", github.url, id),
+        };
+        let assets = [
+            asset("api-1.2.0.tar.gz", 1),
+            asset("SHA256SUMS", 2),
+            asset("BROKENSUMS", 3),
+        ];
+        let provider = GitHub::new(&RepositorySettings {
+            repository: "github.com/a/b".to_owned(),
+            token: "token".to_owned(),
+            ..RepositorySettings::default()
+        })
+        .unwrap();
+        let release = Release {
+            tag: "v1.2.0",
+            assets: &assets,
+            provider: &provider,
+        };
+        let dir = std::env::temp_dir().join(format!("deployer-asset-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let settings = AssetSettings {
+            name: "api-{version}.tar.gz".to_owned(),
+            checksums: "SHA256SUMS".to_owned(),
+        };
+        let output = fetch(&settings, &release, &dir).await.unwrap();
+        assert_eq!(fs::read(output.join("api")).unwrap(), b"#!/bin/sh\n");
+        let request = &github.requests()[0];
+        assert!(request.contains("accept: application/octet-stream"));
+        assert!(request.contains("authorization: token token"));
+
+        let settings = AssetSettings {
+            checksums: "BROKENSUMS".to_owned(),
+            ..settings
+        };
+        let error = fetch(&settings, &release, &dir).await.unwrap_err();
+        assert!(error.starts_with("checksum of api-1.2.0.tar.gz does not match"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
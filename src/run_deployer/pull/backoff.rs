@@ -0,0 +1,81 @@
+// Waiting after failed polls. Errors back off exponentially,
+// rate-limited responses wait as long as the provider asks.
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use tokio::time::Duration;
+
+const MIN_DELAY: Duration = Duration::from_secs(5);
+const MAX_DELAY: Duration = Duration::from_secs(600);
+
+/// Exponential backoff for failed polls:
+/// 5s, 10s, 20s, ... up to 10 minutes.
+#[derive(Default)]
+pub struct Backoff {
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn next(&mut self) -> Duration {
+        let delay = match self.current {
+            Some(delay) => (delay * 2).min(MAX_DELAY),
+            None => MIN_DELAY,
+        };
+        self.current = Some(delay);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+/// How long GitHub asks us to wait before the next request.
+/// Looks at `Retry-After` first, then at `X-RateLimit-Remaining`
+/// and `X-RateLimit-Reset`. `now` is a unix timestamp.
+pub fn rate_limit_delay(headers: &HeaderMap, now: i64) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(seconds) = header(RETRY_AFTER.as_str()).and_then(|v| v.parse::<u64>().ok()) {
+        return Some(Duration::from_secs(seconds));
+    }
+    if header("X-RateLimit-Remaining")? != "0" {
+        return None;
+    }
+    let reset: i64 = header("X-RateLimit-Reset")?.parse().ok()?;
+    // A second extra so we don't come back right before the reset.
+    Some(Duration::from_secs((reset - now).max(0) as u64 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next(), Duration::from_secs(5));
+        assert_eq!(backoff.next(), Duration::from_secs(10));
+        for _ in 0..10 {
+            backoff.next();
+        }
+        assert_eq!(backoff.next(), MAX_DELAY);
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(rate_limit_delay(&headers, 1000), None);
+
+        headers.insert("X-RateLimit-Remaining", "10".parse().unwrap());
+        headers.insert("X-RateLimit-Reset", "1060".parse().unwrap());
+        assert_eq!(rate_limit_delay(&headers, 1000), None);
+
+        headers.insert("X-RateLimit-Remaining", "0".parse().unwrap());
+        assert_eq!(rate_limit_delay(&headers, 1000), Some(Duration::from_secs(61)));
+
+        headers.insert(RETRY_AFTER, "30".parse().unwrap());
+        assert_eq!(rate_limit_delay(&headers, 1000), Some(Duration::from_secs(30)));
+    }
+}
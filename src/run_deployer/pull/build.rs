@@ -3,16 +3,19 @@
 // such as "package.json", "gleam.toml" or "Cargo.toml".
 
 use crate::run_deployer::pull::{DateTime, Local};
+use std::os::unix::fs::symlink;
 use std::process::Command;
 use std::{
+    fs,
     fmt::Display,
     io::{Error, ErrorKind, Result},
-    path::Path,
+    path::{Path, PathBuf},
     process::ExitStatus,
 };
 use walkdir::{DirEntry, WalkDir};
 use crate::log;
 
+#[allow(dead_code)]
 mod project_trait;
 
 enum KeyFile {
@@ -49,11 +52,10 @@ impl Display for KeyFile {
 }
 
 /// Build a service looking at its `KeyFiles`.
-pub fn build(
-    service_path: &Path,
-    build_dir: &Path,
-    service_name: String
-) -> Result<()> {
+/// Returns path to the built project which is
+/// then moved into place with `move_build`.
+/// With `clean`, build caches are dropped first.
+pub fn build(service_path: &Path, clean: bool) -> Result<PathBuf> {
     let key_file = list_directories(service_path)?;
     log!("Found a key file ({}) in {}", key_file.1, key_file.0.path().display());
     if key_file.1.cmp(KeyFile::Rust) {
@@ -61,51 +63,94 @@ pub fn build(
             .0
             .path()
             .parent()
-            .expect("Failed to get file's parent directory");
-        let status = build_rust(path);
-        log!(
-            "Build command has finished with status: {}",
-            status.expect("Failed to get exit status code")
-        );
-
-        let rs_build_path =
-            format!("{}/target/release", path
-                .to_str().expect("Failed to get rust build path"));
-
-        move_build(Path::new(&rs_build_path), build_dir, service_name)?;
-    } else if key_file.1.cmp(KeyFile::Gleam) {
-        todo!();
-    } else if key_file.1.cmp(KeyFile::Go) {
-        todo!();
-    } else if key_file.1.cmp(KeyFile::NodeJS) {
-        todo!();
+            .ok_or_else(|| Error::other("Failed to get file's parent directory"))?;
+        if clean {
+            // Also empties a `target-dir` shared through `.cargo/config.toml`.
+            let status = Command::new("cargo")
+                .arg("clean")
+                .current_dir(path)
+                .status()?;
+            check_status("cargo clean", status)?;
+        }
+        #[allow(deprecated)]
+        let status = build_rust(path)?;
+        log!("Build command has finished with status: {}", status);
+        if !status.success() {
+            return Err(Error::other(format!("cargo build exited with {}", status)));
+        }
+
+        Ok(path.join("target/release"))
+    } else if key_file.1.cmp(KeyFile::Gleam)
+        || key_file.1.cmp(KeyFile::Go)
+        || key_file.1.cmp(KeyFile::NodeJS)
+    {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Projects with {} are not supported yet", key_file.1),
+        ))
     } else {
-        return Err(Error::new(ErrorKind::Other, "Failed to compare KeyFile."));
+        Err(Error::other("Failed to compare KeyFile."))
     }
-    Ok(())
+}
+
+/// Directory the current release of a service lives in.
+pub fn release_dir(build_dir: &Path, service_name: &str) -> PathBuf {
+    build_dir.join(service_name)
+}
+
+/// Name of a blue/green release slot. Doubles as the
+/// name of its directory and of its supervised process.
+pub fn slot_name(service_name: &str, port: u16) -> String {
+    format!("{}@{}", service_name, port)
+}
+
+/// Port of the live blue/green slot, read from
+/// the `build_dir/name -> name@port` symlink.
+pub fn live_port(build_dir: &Path, service_name: &str) -> Option<u16> {
+    let target = fs::read_link(release_dir(build_dir, service_name)).ok()?;
+    let slot = target.file_name()?.to_str()?;
+    slot.strip_prefix(&format!("{}@", service_name))?.parse().ok()
+}
+
+/// Atomically point `build_dir/name` to `slot`.
+pub fn link_release(build_dir: &Path, service_name: &str, slot: &str) -> Result<()> {
+    let link = release_dir(build_dir, service_name);
+    if link.is_dir() && !link.is_symlink() {
+        // Release from before blue/green was enabled.
+        fs::remove_dir_all(&link)?;
+    }
+    let tmp = build_dir.join(format!(".{}.tmp", service_name));
+    let _ = fs::remove_file(&tmp);
+    symlink(slot, &tmp)?;
+    fs::rename(tmp, link)
 }
 
 /// Move built project to the specified directory.
-fn move_build(project: &Path, destination: &Path, service_name: String) -> Result<ExitStatus> {
-    let tmp = format!("{}/{}", destination.to_str().unwrap(), service_name);
-    let destination = Path::new(&tmp);
+pub fn move_build(project: &Path, destination: &Path, service_name: &str) -> Result<ExitStatus> {
+    let tmp = release_dir(destination, service_name);
+    let destination = tmp.as_path();
     if Path::exists(destination) {
         let mut cmd = Command::new("rm")
             .arg("-rf")
             .arg(destination)
-            .spawn()
-            .expect("Failed to remove existing service.");
-        cmd.wait()?;
+            .spawn()?;
+        check_status("rm", cmd.wait()?)?;
     }
     let mut cmd = Command::new("mv")
         .arg(project)
         .arg(destination)
-        .spawn()
-        .expect("Failed to move release");
-    cmd.wait()
+        .spawn()?;
+    check_status("mv", cmd.wait()?)
+}
+
+fn check_status(command: &str, status: ExitStatus) -> Result<ExitStatus> {
+    if !status.success() {
+        return Err(Error::other(format!("{} exited with {}", command, status)));
+    }
+    Ok(status)
 }
 
-/// Panics if fails to spawn the CMD.
+/// Fails if the CMD can't be spawned.
 #[deprecated(
     since = "0.2.2",
     note = "Use structures of projects that implement `Project trait`."
@@ -115,8 +160,7 @@ fn build_rust(path: &Path) -> Result<ExitStatus> {
         .arg("build")
         .arg("--release")
         .current_dir(path)
-        .spawn()
-        .expect("Failed to build Rust project");
+        .spawn()?;
     cmd.wait()
 }
 
@@ -125,7 +169,7 @@ fn list_directories(path: &Path) -> Result<(DirEntry, KeyFile)> {
     for entry in WalkDir::new(path).follow_links(true).into_iter() {
         let tmp = entry?;
         if tmp.path().is_file() {
-            let file_name = tmp.path().file_name().unwrap().to_str().unwrap();
+            let file_name = tmp.file_name().to_str().unwrap_or_default();
             match file_name {
                 "package.json" => return Ok((tmp, KeyFile::NodeJS)),
                 "Cargo.toml" => return Ok((tmp, KeyFile::Rust)),
@@ -134,8 +178,33 @@ fn list_directories(path: &Path) -> Result<(DirEntry, KeyFile)> {
             }
         }
     }
-    Err(Error::new(
-        ErrorKind::Other,
-        "Couldn't find any supported key-file.",
-    ))
+    Err(Error::other("Couldn't find any supported key-file."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_build() {
+        let dir = std::env::temp_dir().join(format!("deployer-build-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        let stale = dir.join("target/release/stale");
+
+        assert_eq!(build(&dir, false).unwrap(), dir.join("target/release"));
+        fs::write(&stale, "").unwrap();
+        build(&dir, false).unwrap();
+        assert!(stale.exists());
+        assert_eq!(build(&dir, true).unwrap(), dir.join("target/release"));
+        assert!(!stale.exists());
+        assert!(dir.join("target/release/app").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -22,8 +22,7 @@ impl<'a> Project for Rust<'a> {
             .arg("build")
             .arg("--release")
             .current_dir(current_dir)
-            .spawn()
-            .expect("Failed to build Rust project");
+            .spawn()?;
         cmd.wait()
     }
 
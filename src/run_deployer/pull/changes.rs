@@ -0,0 +1,105 @@
+// Monorepo path filters. A service is only rebuilt if a file in
+// its `root_dir` or one of its `watch_paths` changed since the
+// commit it was last deployed from. Whenever that commit can't
+// be compared, for instance after a force push, it is rebuilt.
+
+use git2::{Oid, Repository};
+use glob::Pattern;
+use std::path::{Path, PathBuf};
+
+/// Files of the repository to watch for one service.
+pub struct Watch {
+    /// `root_dir` relative to the repository, empty for all of it.
+    dir: PathBuf,
+    patterns: Vec<Pattern>,
+}
+
+impl Watch {
+    /// Fails if a glob of `watch_paths` can't be parsed.
+    pub fn new(dir: PathBuf, watch_paths: &[String]) -> Result<Self, String> {
+        let patterns = watch_paths
+            .iter()
+            .map(|glob| {
+                Pattern::new(glob).map_err(|e| format!("invalid pattern \"{}\": {}", glob, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Watch { dir, patterns })
+    }
+
+    fn matches(&self, file: &Path) -> bool {
+        file.starts_with(&self.dir) || self.patterns.iter().any(|p| p.matches_path(file))
+    }
+}
+
+/// Whether a file watched by `watch` differs between commits
+/// `from` and `to` of the repository at `path`. Blocks.
+/// True if `from` is not part of the repository.
+pub fn changed(path: &Path, from: &str, to: &str, watch: &Watch) -> Result<bool, git2::Error> {
+    let repository = Repository::open(path)?;
+    let tree = |sha: &str| {
+        Oid::from_str(sha)
+            .and_then(|oid| repository.find_commit(oid))
+            .and_then(|commit| commit.tree())
+    };
+    let Ok(old) = tree(from) else {
+        return Ok(true);
+    };
+    let diff = repository.diff_tree_to_tree(Some(&old), Some(&tree(to)?), None)?;
+    Ok(diff.deltas().any(|delta| {
+        [delta.old_file().path(), delta.new_file().path()]
+            .into_iter()
+            .flatten()
+            .any(|file| watch.matches(file))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_changed() {
+        let dir = std::env::temp_dir().join(format!("deployer-changes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repository = Repository::init(&dir).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let commit = |file: &str| {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, file).unwrap();
+            let mut index = repository.index().unwrap();
+            index.add_path(Path::new(file)).unwrap();
+            let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+            let parent = repository.head().ok().map(|h| h.peel_to_commit().unwrap());
+            repository
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    file,
+                    &tree,
+                    &parent.iter().collect::<Vec<_>>(),
+                )
+                .unwrap()
+                .to_string()
+        };
+        let first = commit("api/main.rs");
+        let second = commit("web/index.html");
+        let third = commit("shared/types.rs");
+
+        let api = Watch::new(PathBuf::from("api"), &["shared/**".to_owned()]).unwrap();
+        let web = Watch::new(PathBuf::from("web"), &[]).unwrap();
+        let all = Watch::new(PathBuf::new(), &[]).unwrap();
+        let changed = |from: &str, to: &str, watch| changed(&dir, from, to, watch).unwrap();
+        assert!(!changed(&first, &second, &api));
+        assert!(changed(&first, &second, &web));
+        assert!(changed(&second, &third, &api));
+        assert!(!changed(&second, &third, &web));
+        assert!(changed(&second, &third, &all));
+        // Unknown commit, say after a force push.
+        assert!(changed(&"0".repeat(40), &third, &web));
+        assert!(Watch::new(PathBuf::new(), &["[".to_owned()]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+// Gate on CI. With `required_checks`, a commit is only deployed
+// once every named check run or commit status context has
+// succeeded. Pending ones are checked again on the next poll,
+// failed ones are skipped.
+
+use crate::run_deployer::provider::SourceProvider;
+use reqwest::Client;
+
+/// Where a provider reports the results of CI.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckSource {
+    /// GitHub check runs, as created by GitHub Actions.
+    CheckRuns,
+    /// Commit statuses, one per context.
+    Statuses,
+}
+
+/// Check run or commit status of a commit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    /// Newer runs and statuses have higher ids.
+    pub id: u64,
+    pub name: String,
+    pub state: CheckState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckState {
+    Pending,
+    Success,
+    Failure,
+}
+
+/// What the required checks say about a commit.
+#[derive(Debug, PartialEq)]
+pub enum Gate {
+    Pass,
+    /// Checks that have not finished or not even started.
+    Pending(Vec<String>),
+    Fail(Vec<String>),
+}
+
+/// Compare `checks` to `required`. A check that has not
+/// reported yet is pending, one that ran several times
+/// counts with its latest run, so a failed run that was
+/// re-run successfully passes.
+pub fn gate(required: &[String], checks: &[Check]) -> Gate {
+    let mut pending = Vec::new();
+    let mut failed = Vec::new();
+    for name in required {
+        let latest = checks
+            .iter()
+            .filter(|check| check.name == *name)
+            .max_by_key(|check| check.id);
+        match latest.map(|check| check.state) {
+            Some(CheckState::Success) => {}
+            Some(CheckState::Failure) => failed.push(name.clone()),
+            Some(CheckState::Pending) | None => pending.push(name.clone()),
+        }
+    }
+    if !failed.is_empty() {
+        Gate::Fail(failed)
+    } else if !pending.is_empty() {
+        Gate::Pending(pending)
+    } else {
+        Gate::Pass
+    }
+}
+
+/// Check runs and statuses of commit `sha`,
+/// from every source the provider has.
+pub async fn fetch(
+    provider: &dyn SourceProvider,
+    sha: &str,
+    client: &Client,
+) -> Result<Vec<Check>, String> {
+    let mut checks = Vec::new();
+    for source in [CheckSource::CheckRuns, CheckSource::Statuses] {
+        let Some(url) = provider.checks_url(sha, source) else {
+            continue;
+        };
+        let res = provider
+            .authorize(client.get(&url))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach {url}: {e}"))?;
+        if !res.status().is_success() {
+            return Err(format!(
+                "Failed to fetch checks of {}: {}",
+                sha,
+                res.status()
+            ));
+        }
+        let body = res
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read response: {e}"))?;
+        checks.extend(
+            provider
+                .checks(&body, source)
+                .map_err(|e| format!("Unexpected response: {e}"))?,
+        );
+    }
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_conf::file_struct::RepositorySettings;
+    use crate::run_deployer::{provider::GitHub, stand_in::StandIn};
+
+    #[test]
+    fn test_gate() {
+        let check = |id, name: &str, state| Check {
+            id,
+            name: name.to_owned(),
+            state,
+        };
+        let required = ["build".to_owned(), "ci/lint".to_owned()];
+        let checks = [
+            check(1, "build", CheckState::Success),
+            check(2, "ci/lint", CheckState::Success),
+            check(3, "optional", CheckState::Failure),
+        ];
+        assert_eq!(gate(&required, &checks), Gate::Pass);
+
+        let checks = [check(1, "build", CheckState::Pending)];
+        assert_eq!(gate(&required, &checks), Gate::Pending(required.to_vec()));
+
+        let checks = [
+            check(1, "build", CheckState::Success),
+            check(2, "build", CheckState::Failure),
+            check(3, "ci/lint", CheckState::Pending),
+        ];
+        assert_eq!(
+            gate(&required, &checks),
+            Gate::Fail(vec!["build".to_owned()])
+        );
+
+        // Failed jobs that were re-run successfully.
+        let checks = [
+            check(4, "build", CheckState::Success),
+            check(1, "build", CheckState::Failure),
+            check(2, "ci/lint", CheckState::Failure),
+            check(3, "ci/lint", CheckState::Success),
+        ];
+        assert_eq!(gate(&required, &checks), Gate::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_check_runs_and_statuses() {
+        let runs = br#"{ "check_runs": [
+            { "name": "build", "status": "completed", "conclusion": "success" }
+        ] }"#;
+        let status = br#"{ "state": "pending", "statuses": [
+            { "context": "ci/lint", "state": "pending" }
+        ] }"#;
+        let github = StandIn::start(vec![
+            (
+                "/api/v3/repos/a/b/commits/abc/check-runs?per_page=100",
+                200,
+                runs.to_vec(),
+            ),
+            (
+                "/api/v3/repos/a/b/commits/abc/status?per_page=100",
+                200,
+                status.to_vec(),
+            ),
+        ])
+        .await;
+        let provider = GitHub::new(&RepositorySettings {
+            repository: "ghe.corp/a/b".to_owned(),
+            base_url: Some(github.url.clone()),
+            ..RepositorySettings::default()
+        })
+        .unwrap();
+        let checks = fetch(&provider, "abc", &Client::new()).await.unwrap();
+        let required = ["build".to_owned(), "ci/lint".to_owned()];
+        assert_eq!(
+            gate(&required, &checks),
+            Gate::Pending(vec!["ci/lint".to_owned()])
+        );
+        assert!(fetch(&provider, "def", &Client::new()).await.is_err());
+    }
+}
@@ -0,0 +1,217 @@
+// Directives in commit messages. `[skip deploy]` deploys nothing,
+// `[deploy: api, web]` only the named services and `[clean build]`
+// rebuilds services without build caches, even if none of their
+// files changed. With `directives: "since_last_deploy"` the
+// messages of every commit since the last deploy count, and a
+// service is deployed if any of them asks for it.
+
+use crate::generate_conf::file_struct::Service;
+use git2::{Oid, Repository};
+use std::{collections::BTreeSet, path::Path};
+
+/// What the commit messages ask for.
+#[derive(Debug, Default, PartialEq)]
+pub struct Directives {
+    /// Services to deploy, `None` for all of them.
+    pub services: Option<BTreeSet<String>>,
+    /// Drop build caches and rebuild services whose
+    /// files didn't change.
+    pub clean: bool,
+}
+
+impl Directives {
+    /// Directives of one commit message. Case doesn't matter.
+    pub fn parse(message: &str) -> Self {
+        let mut directives = Directives::default();
+        let mut skip = false;
+        for directive in message.split('[').skip(1).filter_map(|s| s.split_once(']')) {
+            let directive = directive.0.trim().to_lowercase();
+            if directive == "skip deploy" {
+                skip = true;
+            } else if directive == "clean build" {
+                directives.clean = true;
+            } else if let Some(names) = directive.strip_prefix("deploy:") {
+                directives
+                    .services
+                    .get_or_insert_with(BTreeSet::new)
+                    .extend(
+                        names
+                            .split(|c: char| c == ',' || c.is_whitespace())
+                            .filter(|name| !name.is_empty())
+                            .map(str::to_owned),
+                    );
+            }
+        }
+        if skip {
+            directives.services = Some(BTreeSet::new());
+        }
+        directives
+    }
+
+    /// Directives of both commits.
+    fn merge(self, other: Self) -> Self {
+        let services = match (self.services, other.services) {
+            (Some(mut a), Some(b)) => {
+                a.extend(b);
+                Some(a)
+            }
+            _ => None,
+        };
+        Directives {
+            services,
+            clean: self.clean || other.clean,
+        }
+    }
+
+    pub fn skip(&self) -> bool {
+        self.services.as_ref().is_some_and(BTreeSet::is_empty)
+    }
+
+    /// Whether `service` should be deployed. Named either by its
+    /// unit, say `api-staging`, or by its name in the config, `api`.
+    pub fn includes(&self, service: &Service) -> bool {
+        self.services
+            .as_ref()
+            .is_none_or(|services| services.iter().any(|name| names(name, service)))
+    }
+}
+
+/// Whether `name` of a `[deploy: ...]` directive names `service`.
+pub fn names(name: &str, service: &Service) -> bool {
+    [&service.name, service.base_name()]
+        .iter()
+        .any(|s| s.to_lowercase() == name)
+}
+
+/// Directives of commit `sha` in the repository at `path`,
+/// and of every commit since `since` if given. Only `sha`
+/// counts if `since` is `sha` or not part of the repository.
+/// Blocks.
+pub fn read(path: &Path, sha: &str, since: Option<&str>) -> Result<Directives, git2::Error> {
+    let repository = Repository::open(path)?;
+    let head = Oid::from_str(sha)?;
+    let since = since
+        .and_then(|since| Oid::from_str(since).ok())
+        .filter(|since| *since != head && repository.find_commit(*since).is_ok());
+    let Some(since) = since else {
+        let commit = repository.find_commit(head)?;
+        return Ok(Directives::parse(commit.message().unwrap_or_default()));
+    };
+    let mut walk = repository.revwalk()?;
+    walk.push(head)?;
+    walk.hide(since)?;
+    let mut directives: Option<Directives> = None;
+    for oid in walk {
+        let commit = repository.find_commit(oid?)?;
+        let parsed = Directives::parse(commit.message().unwrap_or_default());
+        directives = Some(match directives {
+            Some(directives) => directives.merge(parsed),
+            None => parsed,
+        });
+    }
+    Ok(directives.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_conf::file_struct::{ConfigFile, EnvironmentSettings};
+    use std::fs;
+
+    fn services(names: &[&str]) -> Option<BTreeSet<String>> {
+        Some(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Directives::parse("Fix typo"), Directives::default());
+        assert!(Directives::parse("Update docs [skip deploy]").skip());
+        assert!(Directives::parse("[Skip Deploy] [deploy: api]").skip());
+
+        let directives = Directives::parse("Fix login\n\n[deploy: api, Web] [deploy: worker]");
+        assert_eq!(directives.services, services(&["api", "web", "worker"]));
+        assert!(directives.includes(&service("web")) && !directives.includes(&service("admin")));
+        assert!(!directives.skip());
+
+        let directives = Directives::parse("Bump toolchain [clean build]");
+        assert!(directives.clean);
+        assert!(directives.includes(&service("admin")));
+    }
+
+    fn service(name: &str) -> Service {
+        Service {
+            name: name.to_owned(),
+            ..Service::default()
+        }
+    }
+
+    #[test]
+    fn test_includes_environment_units() {
+        let environment = |name: &str| EnvironmentSettings {
+            name: name.to_owned(),
+            branch: name.to_owned(),
+            track: None,
+            build_root: None,
+            env: Default::default(),
+            unit: "{service}-{environment}".to_owned(),
+            port_offset: 0,
+        };
+        let mut config = ConfigFile {
+            services: vec![service("api"), service("web")],
+            environments: vec![environment("staging"), environment("production")],
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        let staging = &config.repositories[0].services;
+        let production = &config.repositories[1].services;
+        assert_eq!(staging[0].name, "api-staging");
+
+        let directives = Directives::parse("[deploy: API]");
+        assert!(directives.includes(&staging[0]) && directives.includes(&production[0]));
+        assert!(!directives.includes(&staging[1]));
+
+        let directives = Directives::parse("[deploy: api-staging]");
+        assert!(directives.includes(&staging[0]));
+        assert!(!directives.includes(&production[0]) && !directives.includes(&staging[1]));
+    }
+
+    #[test]
+    fn test_read_since_last_deploy() {
+        let dir = std::env::temp_dir().join(format!("deployer-directives-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repository = Repository::init(&dir).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = repository
+            .find_tree(repository.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = |message: &str| {
+            let parent = repository.head().ok().map(|h| h.peel_to_commit().unwrap());
+            repository
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    message,
+                    &tree,
+                    &parent.iter().collect::<Vec<_>>(),
+                )
+                .unwrap()
+                .to_string()
+        };
+        let deployed = commit("[deploy: web]");
+        commit("Fix login [deploy: api]");
+        commit("Docs [skip deploy]");
+        let head = commit("Tidy up [deploy: worker] [clean build]");
+
+        let directives = read(&dir, &head, None).unwrap();
+        assert_eq!(directives.services, services(&["worker"]));
+        let directives = read(&dir, &head, Some(&deployed)).unwrap();
+        assert_eq!(directives.services, services(&["api", "worker"]));
+        assert!(directives.clean);
+        let directives = read(&dir, &head, Some(&head)).unwrap();
+        assert_eq!(directives.services, services(&["worker"]));
+        let directives = read(&dir, &head, Some(&"0".repeat(40))).unwrap();
+        assert_eq!(directives.services, services(&["worker"]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
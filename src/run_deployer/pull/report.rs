@@ -0,0 +1,209 @@
+// Deploys reported back to GitHub. Every deployed service gets a
+// GitHub Deployment with an in_progress status while it is built
+// and a success or failure status once it is live or has failed,
+// plus a commit status if asked for. Failing to report is logged
+// and never fails the deploy itself.
+
+use crate::generate_conf::file_struct::{ReportSettings, RepositorySettings};
+use crate::log;
+use crate::run_deployer::provider::{GitHub, SourceProvider};
+use chrono::{DateTime, Local};
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Client,
+};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+/// `POST /repos/{owner}/{repo}/deployments`.
+#[derive(Deserialize)]
+struct DeploymentResponse {
+    id: u64,
+}
+
+/// Deployment of one service, created by `start`.
+pub struct Deployment {
+    github: GitHub,
+    id: u64,
+    /// `<environment>/<service>`.
+    environment: String,
+    commit: String,
+    log_url: Option<String>,
+    commit_status: bool,
+}
+
+/// Create the deployment of `service`, its name without the
+/// environment, at `commit` and mark it in_progress. `None`
+/// if GitHub could not be told.
+pub async fn start(
+    report: &ReportSettings,
+    settings: &RepositorySettings,
+    service: &str,
+    commit: &str,
+    client: &Client,
+) -> Option<Deployment> {
+    let github = match GitHub::new(settings) {
+        Ok(github) => github,
+        Err(e) => {
+            log!("Failed to report the deploy of {}: {}", service, e);
+            return None;
+        }
+    };
+    let environment = settings
+        .environment
+        .as_deref()
+        .or(report.environment.as_deref())
+        .unwrap_or("production");
+    let log_url = report.log_url.as_ref().map(|url| {
+        url.replace("{environment}", environment)
+            .replace("{service}", service)
+            .replace("{commit}", commit)
+    });
+    let environment = format!("{}/{}", environment, service);
+    let body = json!({
+        "ref": commit,
+        "environment": environment,
+        "description": format!("Deploy {} with Deployer", service),
+        // Checks are Deployer's business, see `required_checks`.
+        "required_contexts": [],
+        "auto_merge": false,
+        "payload": { "service": service },
+    });
+    let url = format!("{}/deployments", github.api());
+    let id = match post(&github, &url, body, client).await {
+        Ok(response) => match serde_json::from_str::<DeploymentResponse>(&response) {
+            Ok(deployment) => deployment.id,
+            Err(e) => {
+                log!("Failed to create the deployment of {}: {}", service, e);
+                return None;
+            }
+        },
+        Err(e) => {
+            log!("Failed to create the deployment of {}: {}", service, e);
+            return None;
+        }
+    };
+    let deployment = Deployment {
+        github,
+        id,
+        environment,
+        commit: commit.to_owned(),
+        log_url,
+        commit_status: report.commit_status,
+    };
+    deployment.status("in_progress", client).await;
+    Some(deployment)
+}
+
+impl Deployment {
+    /// Mark the deployment as successful or failed.
+    pub async fn finish(&self, success: bool, client: &Client) {
+        let state = if success { "success" } else { "failure" };
+        self.status(state, client).await;
+        if !self.commit_status {
+            return;
+        }
+        let mut body = json!({
+            "state": state,
+            "context": format!("deployer/{}", self.environment),
+            "description": if success { "Deployed" } else { "Deploy failed" },
+        });
+        if let Some(log_url) = &self.log_url {
+            body["target_url"] = json!(log_url);
+        }
+        let url = format!("{}/statuses/{}", self.github.api(), self.commit);
+        if let Err(e) = post(&self.github, &url, body, client).await {
+            log!(
+                "Failed to set the commit status of {}: {}",
+                self.environment,
+                e
+            );
+        }
+    }
+
+    async fn status(&self, state: &str, client: &Client) {
+        let mut body = json!({ "state": state });
+        if let Some(log_url) = &self.log_url {
+            body["log_url"] = json!(log_url);
+        }
+        let url = format!("{}/deployments/{}/statuses", self.github.api(), self.id);
+        if let Err(e) = post(&self.github, &url, body, client).await {
+            log!("Failed to report {} of {}: {}", state, self.environment, e);
+        }
+    }
+}
+
+/// POST `body` to `url` and return the response.
+async fn post(github: &GitHub, url: &str, body: Value, client: &Client) -> Result<String, String> {
+    let res = github
+        .authorize(client.post(url))
+        .header(ACCEPT, "application/vnd.github+json")
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("GitHub answered with {}", res.status()));
+    }
+    res.text().await.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_deployer::stand_in::StandIn;
+
+    #[tokio::test]
+    async fn test_report_deployment() {
+        let github = StandIn::start(vec![
+            (
+                "/api/v3/repos/a/b/deployments",
+                201,
+                br#"{ "id": 5 }"#.to_vec(),
+            ),
+            (
+                "/api/v3/repos/a/b/deployments/5/statuses",
+                201,
+                b"{}".to_vec(),
+            ),
+            ("/api/v3/repos/a/b/statuses/abc", 201, b"{}".to_vec()),
+        ])
+        .await;
+        let settings = RepositorySettings {
+            repository: "ghe.corp/a/b".to_owned(),
+            base_url: Some(github.url.clone()),
+            environment: Some("staging".to_owned()),
+            ..RepositorySettings::default()
+        };
+        let report = ReportSettings {
+            environment: None,
+            log_url: Some("https://ci.example.com/{environment}/{service}/{commit}".to_owned()),
+            commit_status: true,
+        };
+        let client = Client::new();
+        let deployment = start(&report, &settings, "api", "abc", &client)
+            .await
+            .unwrap();
+        deployment.finish(false, &client).await;
+
+        let bodies: Vec<Value> = github
+            .requests()
+            .iter()
+            .map(|request| {
+                let (_, body) = request.split_once("\r\n\r\n").unwrap();
+                serde_json::from_str(body).unwrap()
+            })
+            .collect();
+        assert_eq!(bodies[0]["ref"], "abc");
+        assert_eq!(bodies[0]["environment"], "staging/api");
+        assert_eq!(bodies[1]["state"], "in_progress");
+        assert_eq!(
+            bodies[1]["log_url"],
+            "https://ci.example.com/staging/api/abc"
+        );
+        assert_eq!(bodies[2]["state"], "failure");
+        assert_eq!(bodies[3]["context"], "deployer/staging/api");
+        assert_eq!(bodies[3]["state"], "failure");
+    }
+}
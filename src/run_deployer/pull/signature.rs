@@ -0,0 +1,318 @@
+// Commit signature verification. Locally, the signature is taken
+// out of the cloned commit and checked with `ssh-keygen -Y verify`
+// against an allowed signers file or with `gpgv` against a keyring
+// of trusted keys. Or GitHub's own verification is trusted.
+
+use crate::generate_conf::file_struct::SignatureSettings;
+use crate::run_deployer::provider::{GitHub, SourceProvider};
+use git2::{Oid, Repository};
+use reqwest::Client;
+use serde_derive::Deserialize;
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Whether a commit may be deployed.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// Signed by the named key or principal.
+    Trusted(String),
+    /// Why the commit is refused.
+    Refused(String),
+}
+
+/// `GET /repos/{owner}/{repo}/commits/{sha}`.
+#[derive(Deserialize)]
+struct CommitResponse {
+    commit: CommitDetails,
+}
+
+#[derive(Deserialize)]
+struct CommitDetails {
+    verification: Verification,
+}
+
+#[derive(Deserialize)]
+struct Verification {
+    verified: bool,
+    reason: String,
+}
+
+/// Ask GitHub whether it verified the signature of `sha`.
+/// Fails if GitHub can't be asked.
+pub async fn verify_github(github: &GitHub, sha: &str, client: &Client) -> Result<Verdict, String> {
+    let url = format!("{}/commits/{}", github.api(), sha);
+    let res = github
+        .authorize(client.get(&url))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach {url}: {e}"))?;
+    if !res.status().is_success() {
+        return Err(format!("Failed to fetch commit {}: {}", sha, res.status()));
+    }
+    let body = res
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read response: {e}"))?;
+    let verification = serde_json::from_str::<CommitResponse>(&body)
+        .map_err(|e| format!("Unexpected response: {e}"))?
+        .commit
+        .verification;
+    Ok(if verification.verified {
+        Verdict::Trusted("GitHub".to_owned())
+    } else {
+        Verdict::Refused(format!(
+            "{} is not verified by GitHub ({})",
+            sha, verification.reason
+        ))
+    })
+}
+
+/// Verify the signature of commit `sha` in the clone at `path`.
+/// Blocks. Fails if the verifying tool can't be run.
+pub fn verify_local(
+    path: &Path,
+    sha: &str,
+    settings: &SignatureSettings,
+) -> Result<Verdict, String> {
+    let repository = Repository::open(path).map_err(|e| e.to_string())?;
+    let oid = Oid::from_str(sha).map_err(|e| e.to_string())?;
+    let (signature, data) = match repository.extract_signature(&oid, None) {
+        Ok(signed) => signed,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            return Ok(Verdict::Refused(format!("{} is not signed", sha)));
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    let signature = signature.as_str().unwrap_or_default();
+    let git_dir = repository.path();
+    if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+        match &settings.allowed_signers {
+            Some(allowed) => verify_ssh(Path::new(allowed), signature, &data, git_dir, sha),
+            None => Ok(Verdict::Refused(format!(
+                "{} has an SSH signature but no allowed_signers is set",
+                sha
+            ))),
+        }
+    } else if signature.starts_with("-----BEGIN PGP SIGNATURE-----") {
+        match &settings.gpg_keyring {
+            Some(keyring) => verify_gpg(Path::new(keyring), signature, &data, git_dir, sha),
+            None => Ok(Verdict::Refused(format!(
+                "{} has a GPG signature but no gpg_keyring is set",
+                sha
+            ))),
+        }
+    } else {
+        Ok(Verdict::Refused(format!(
+            "{} has a signature of unknown format",
+            sha
+        )))
+    }
+}
+
+/// Find the principal of the key in `allowed` and verify with it.
+fn verify_ssh(
+    allowed: &Path,
+    signature: &str,
+    data: &[u8],
+    git_dir: &Path,
+    sha: &str,
+) -> Result<Verdict, String> {
+    let file = SignatureFile::write(git_dir, signature)?;
+    let found = Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-f"])
+        .arg(allowed)
+        .arg("-s")
+        .arg(&file.0)
+        .output()
+        .map_err(|e| format!("Failed to run ssh-keygen: {}", e))?;
+    let principals = String::from_utf8_lossy(&found.stdout);
+    let Some(principal) = principals.lines().next().filter(|_| found.status.success()) else {
+        return Ok(Verdict::Refused(format!(
+            "{} is signed by a key that is not in {}",
+            sha,
+            allowed.display()
+        )));
+    };
+    let verified = run_with_input(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", "git", "-f"])
+            .arg(allowed)
+            .args(["-I", principal, "-s"])
+            .arg(&file.0),
+        data,
+    )?;
+    Ok(if verified {
+        Verdict::Trusted(principal.to_owned())
+    } else {
+        Verdict::Refused(format!("{} has a bad signature", sha))
+    })
+}
+
+fn verify_gpg(
+    keyring: &Path,
+    signature: &str,
+    data: &[u8],
+    git_dir: &Path,
+    sha: &str,
+) -> Result<Verdict, String> {
+    let file = SignatureFile::write(git_dir, signature)?;
+    let verified = run_with_input(
+        Command::new("gpgv")
+            .arg("--keyring")
+            .arg(keyring)
+            .arg(&file.0)
+            .arg("-"),
+        data,
+    )?;
+    Ok(if verified {
+        Verdict::Trusted(format!("a key in {}", keyring.display()))
+    } else {
+        Verdict::Refused(format!(
+            "{} is not signed by a key in {}",
+            sha,
+            keyring.display()
+        ))
+    })
+}
+
+/// Run `command` with `input` on stdin. True if it succeeded.
+fn run_with_input(command: &mut Command, input: &[u8]) -> Result<bool, String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input)
+            .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    }
+    let status = child
+        .wait()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    Ok(status.success())
+}
+
+/// Signature written to a file only Deployer can read, in a
+/// directory of its own inside the `.git` directory of the
+/// clone. Removed on drop.
+struct SignatureFile(PathBuf);
+
+impl SignatureFile {
+    fn write(git_dir: &Path, signature: &str) -> Result<Self, String> {
+        let dir = git_dir.join("deployer-signature");
+        let error = |e: std::io::Error| format!("{}: {}", dir.display(), e);
+        let _ = fs::remove_dir_all(&dir);
+        DirBuilder::new().mode(0o700).create(&dir).map_err(error)?;
+        let file = SignatureFile(dir.join("signature"));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&file.0)
+            .and_then(|mut f| f.write_all(signature.as_bytes()))
+            .map_err(error)?;
+        Ok(file)
+    }
+}
+
+impl Drop for SignatureFile {
+    fn drop(&mut self) {
+        if let Some(dir) = self.0.parent() {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Commit signed with `key`, or unsigned without it.
+    fn commit(repository: &Repository, key: Option<&Path>) -> Oid {
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = repository
+            .find_tree(repository.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let Some(key) = key else {
+            return repository
+                .commit(Some("HEAD"), &signature, &signature, "unsigned", &tree, &[])
+                .unwrap();
+        };
+        let buffer = repository
+            .commit_create_buffer(&signature, &signature, "signed", &tree, &[])
+            .unwrap();
+        let mut sign = Command::new("ssh-keygen")
+            .args(["-Y", "sign", "-n", "git", "-f"])
+            .arg(key)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        sign.stdin.take().unwrap().write_all(&buffer).unwrap();
+        let output = sign.wait_with_output().unwrap();
+        let ssh_signature = String::from_utf8(output.stdout).unwrap();
+        repository
+            .commit_signed(buffer.as_str().unwrap(), &ssh_signature, None)
+            .unwrap()
+    }
+
+    fn keygen(path: &Path) -> String {
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        fs::read_to_string(path.with_extension("pub")).unwrap()
+    }
+
+    #[test]
+    fn test_verify_ssh_signatures() {
+        let dir = std::env::temp_dir().join(format!("deployer-signature-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repository = Repository::init(dir.join("repo")).unwrap();
+        let trusted = keygen(&dir.join("trusted"));
+        keygen(&dir.join("other"));
+        let allowed = dir.join("allowed_signers");
+        fs::write(&allowed, format!("ci@example.com {}", trusted)).unwrap();
+        let settings = SignatureSettings {
+            allowed_signers: Some(allowed.to_string_lossy().into_owned()),
+            ..SignatureSettings::default()
+        };
+        let verify = |oid: Oid| verify_local(&dir.join("repo"), &oid.to_string(), &settings);
+
+        let signed = commit(&repository, Some(&dir.join("trusted")));
+        assert_eq!(
+            verify(signed).unwrap(),
+            Verdict::Trusted("ci@example.com".to_owned())
+        );
+        let unsigned = commit(&repository, None);
+        assert_eq!(
+            verify(unsigned).unwrap(),
+            Verdict::Refused(format!("{} is not signed", unsigned))
+        );
+        let untrusted = commit(&repository, Some(&dir.join("other")));
+        assert!(matches!(verify(untrusted).unwrap(), Verdict::Refused(_)));
+
+        let git_dir = repository.path();
+        let file = SignatureFile::write(git_dir, "signature").unwrap();
+        let mode = |path: &Path| {
+            use std::os::unix::fs::PermissionsExt;
+            fs::metadata(path).unwrap().permissions().mode() & 0o777
+        };
+        assert_eq!(mode(&file.0), 0o600);
+        assert_eq!(mode(file.0.parent().unwrap()), 0o700);
+        drop(file);
+        assert!(!git_dir.join("deployer-signature").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+// Picks the tag or release to deploy for repositories with
+// `track`. Tags are ordered by their semantic version, so only
+// names like `v1.2.3` or `1.2.3` are considered.
+
+use super::asset::Asset;
+use crate::generate_conf::file_struct::TrackSettings;
+use glob::Pattern;
+use semver::{Version, VersionReq};
+
+/// Tag or release as listed by the provider. Without `sha`
+/// the commit has to be looked up by the tag name.
+#[derive(Debug, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub sha: Option<String>,
+    /// Release is marked as a pre-release.
+    pub prerelease: bool,
+    /// Assets of the release.
+    pub assets: Vec<Asset>,
+}
+
+/// `pattern`, `version` and `prereleases` of a `track`.
+pub struct Filter {
+    pattern: Option<Pattern>,
+    version: Option<VersionReq>,
+    prereleases: bool,
+}
+
+impl Filter {
+    /// Fails if `pattern` or `version` can't be parsed.
+    pub fn new(track: &TrackSettings) -> Result<Self, String> {
+        let pattern = match &track.pattern {
+            Some(pattern) => Some(
+                Pattern::new(pattern)
+                    .map_err(|e| format!("invalid pattern \"{}\": {}", pattern, e))?,
+            ),
+            None => None,
+        };
+        let version = match &track.version {
+            Some(version) => Some(
+                VersionReq::parse(version)
+                    .map_err(|e| format!("invalid version \"{}\": {}", version, e))?,
+            ),
+            None => None,
+        };
+        Ok(Filter {
+            pattern,
+            version,
+            prereleases: track.prereleases,
+        })
+    }
+
+    /// Version of the tag `name` if it should be deployed.
+    fn version(&self, name: &str, prerelease: bool) -> Option<Version> {
+        if let Some(pattern) = &self.pattern {
+            if !pattern.matches(name) {
+                return None;
+            }
+        }
+        let version = Version::parse(name.trim_start_matches(['v', 'V'])).ok()?;
+        if !self.prereleases && (prerelease || !version.pre.is_empty()) {
+            return None;
+        }
+        match &self.version {
+            Some(req) if !req.matches(&version) => None,
+            _ => Some(version),
+        }
+    }
+}
+
+/// Newest of `tags` that matches `track`.
+pub fn newest(track: &TrackSettings, tags: Vec<Tag>) -> Result<Option<Tag>, String> {
+    let filter = Filter::new(track)?;
+    Ok(tags
+        .into_iter()
+        .filter_map(|tag| Some((filter.version(&tag.name, tag.prerelease)?, tag)))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, tag)| tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_conf::file_struct::TrackSource;
+
+    fn track(pattern: Option<&str>, version: Option<&str>) -> TrackSettings {
+        TrackSettings {
+            source: TrackSource::Tags,
+            pattern: pattern.map(str::to_owned),
+            version: version.map(str::to_owned),
+            prereleases: false,
+        }
+    }
+
+    fn tags() -> Vec<Tag> {
+        let tag = |name: &str, prerelease: bool| Tag {
+            name: name.to_owned(),
+            sha: None,
+            prerelease,
+            assets: Vec::new(),
+        };
+        vec![
+            tag("v2.2.0", true),
+            tag("v2.1.0-rc.1", false),
+            tag("v2.0.1", false),
+            tag("v2.0.0", false),
+            tag("nightly", false),
+            tag("v1.10.0", false),
+            tag("v1.9.0", false),
+        ]
+    }
+
+    fn name(track: &TrackSettings) -> Option<String> {
+        newest(track, tags()).unwrap().map(|tag| tag.name)
+    }
+
+    #[test]
+    fn test_newest() {
+        assert_eq!(name(&track(None, None)).as_deref(), Some("v2.0.1"));
+        assert_eq!(name(&track(Some("v1.*"), None)).as_deref(), Some("v1.10.0"));
+        assert_eq!(
+            name(&track(None, Some(">=1.0.0, <2"))).as_deref(),
+            Some("v1.10.0")
+        );
+        assert_eq!(name(&track(None, Some(">=3"))), None);
+
+        let prereleases = TrackSettings {
+            prereleases: true,
+            ..track(None, None)
+        };
+        assert_eq!(name(&prereleases).as_deref(), Some("v2.2.0"));
+        let prereleases = TrackSettings {
+            prereleases: true,
+            ..track(Some("v2.1.*"), None)
+        };
+        assert_eq!(name(&prereleases).as_deref(), Some("v2.1.0-rc.1"));
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        let track = track(Some("v[1"), Some("2.x.y"));
+        let error = Filter::new(&track).err().unwrap();
+        assert!(error.starts_with("invalid pattern"));
+    }
+}
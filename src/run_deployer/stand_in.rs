@@ -0,0 +1,92 @@
+// Local HTTP server standing in for GitHub in tests.
+// Answers every request from a fixed list of routes
+// and keeps the requests for assertions. Every answer
+// has the same ETag, asking with it gets a 304.
+
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+pub struct StandIn {
+    /// `http://127.0.0.1:<port>`.
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+const ETAG: &str = "\"stand-in\"";
+
+/// Path (with the query, if any), status and body.
+pub type Route = (&'static str, u16, Vec<u8>);
+
+impl StandIn {
+    /// Unknown paths are answered with 404.
+    pub async fn start(routes: Vec<Route>) -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                let path = request.split_whitespace().nth(1).unwrap_or("").to_owned();
+                let not_modified = request
+                    .lines()
+                    .any(|line| line.eq_ignore_ascii_case(&format!("if-none-match: {}", ETAG)));
+                received.lock().unwrap().push(request);
+                let (status, body) = routes
+                    .iter()
+                    .find(|(route, _, _)| *route == path)
+                    .map(|(_, status, body)| {
+                        if not_modified {
+                            (304, Vec::new())
+                        } else {
+                            (*status, body.clone())
+                        }
+                    })
+                    .unwrap_or((404, Vec::new()));
+                let head = format!(
+                    "HTTP/1.1 {} Stand-in\r\nETag: {}\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n",
+                    status,
+                    ETAG,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        StandIn { url, requests }
+    }
+
+    /// Raw requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Head and body of one request.
+async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+    let mut data = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = stream.read(&mut buf).await.unwrap_or(0);
+        data.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&data);
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end]
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if n == 0 || data.len() >= end + 4 + length {
+                return text.into_owned();
+            }
+        } else if n == 0 {
+            return text.into_owned();
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// Built-in process supervisor for hosts without systemd.
+// Runs each service's `command` from its current release,
+// restarts it when it crashes and stops it gracefully
+// before a new release is moved in.
+
+use crate::generate_conf::file_struct::{Service, SupervisorSettings};
+use crate::log;
+use chrono::{DateTime, Local};
+use log_file::RotatingLog;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::{Child, Command},
+    sync::oneshot,
+    task::JoinHandle,
+    time::{self, Duration, Instant},
+};
+
+mod log_file;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A process that ran at least this long is considered
+/// healthy, so its next crash starts backoff from scratch.
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+struct Process {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+    state: Arc<Mutex<String>>,
+}
+
+/// Everything a supervised process needs to be (re)started.
+struct Spec {
+    name: String,
+    command: String,
+    dir: PathBuf,
+    env: Vec<(String, String)>,
+    log_dir: PathBuf,
+    max_log_size: u64,
+    max_log_files: usize,
+    stop_timeout: Duration,
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+    processes: Mutex<HashMap<String, Process>>,
+}
+
+impl Supervisor {
+    /// Start `service` from `dir` as process `name`. Usually
+    /// `name` is the service name, blue/green deploys run two
+    /// processes of the same service (`name@port`).
+    /// Does nothing for services without a `command`.
+    ///
+    /// Fails if a process with this name is already running.
+    pub fn start(
+        &self,
+        name: &str,
+        service: &Service,
+        dir: &Path,
+        settings: &SupervisorSettings,
+        env: Vec<(String, String)>,
+    ) -> Result<(), String> {
+        let command = match &service.command {
+            Some(command) => command.clone(),
+            None => return Ok(()),
+        };
+        let mut processes = self.processes.lock().unwrap();
+        if processes.contains_key(name) {
+            return Err(format!("Service {} is already running", name));
+        }
+        if !dir.exists() {
+            return Err(format!(
+                "Release of {} does not exist: {}",
+                service.name,
+                dir.display()
+            ));
+        }
+        fs::create_dir_all(&settings.log_dir)
+            .map_err(|e| format!("Failed to create log directory {}: {e}", settings.log_dir))?;
+
+        let spec = Spec {
+            name: name.to_owned(),
+            command,
+            dir: dir.to_path_buf(),
+            env,
+            log_dir: PathBuf::from(&settings.log_dir),
+            max_log_size: settings.max_log_size,
+            max_log_files: settings.max_log_files,
+            stop_timeout: Duration::from_secs(settings.stop_timeout),
+        };
+        let (stop, stop_rx) = oneshot::channel();
+        let state = Arc::new(Mutex::new("starting".to_owned()));
+        let task = tokio::spawn(supervise(spec, stop_rx, state.clone()));
+        processes.insert(name.to_owned(), Process { stop, task, state });
+        Ok(())
+    }
+
+    /// Gracefully stop the service. Returns `false`
+    /// if it was not running.
+    pub async fn stop(&self, name: &str) -> bool {
+        let process = self.processes.lock().unwrap().remove(name);
+        match process {
+            Some(process) => {
+                let _ = process.stop.send(());
+                let _ = process.task.await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn stop_all(&self) {
+        let names: Vec<String> = self.processes.lock().unwrap().keys().cloned().collect();
+        for name in names {
+            self.stop(&name).await;
+        }
+    }
+
+    /// Human readable state of the process,
+    /// `None` if it is not supervised.
+    pub fn state(&self, name: &str) -> Option<String> {
+        let processes = self.processes.lock().unwrap();
+        processes
+            .get(name)
+            .map(|p| p.state.lock().unwrap().clone())
+    }
+}
+
+async fn supervise(spec: Spec, mut stop: oneshot::Receiver<()>, state: Arc<Mutex<String>>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        match spawn(&spec) {
+            Ok(mut child) => {
+                let pid = child.id().unwrap_or_default();
+                log!("Started {} (pid {})", spec.name, pid);
+                *state.lock().unwrap() = format!("running (pid {})", pid);
+                tokio::select! {
+                    status = child.wait() => {
+                        match status {
+                            Ok(status) => {
+                                log!("{} exited with {}", spec.name, status);
+                            }
+                            Err(e) => {
+                                log!("Failed to wait for {}: {}", spec.name, e);
+                            }
+                        }
+                    }
+                    _ = &mut stop => {
+                        *state.lock().unwrap() = "stopping".to_owned();
+                        terminate(&spec, &mut child).await;
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                log!("Failed to start {}: {}", spec.name, e);
+            }
+        }
+
+        if started.elapsed() >= STABLE_AFTER {
+            backoff = MIN_BACKOFF;
+        }
+        log!("Restarting {} in {}s", spec.name, backoff.as_secs());
+        *state.lock().unwrap() = format!("restarting in {}s", backoff.as_secs());
+        tokio::select! {
+            _ = time::sleep(backoff) => {}
+            _ = &mut stop => return,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn spawn(spec: &Spec) -> std::io::Result<Child> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&spec.command)
+        .current_dir(&spec.dir)
+        .envs(spec.env.iter().cloned())
+        // Own process group so signals reach
+        // everything the command spawns.
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let out = spec.log_dir.join(format!("{}.out.log", spec.name));
+    let err = spec.log_dir.join(format!("{}.err.log", spec.name));
+    if let Some(stdout) = child.stdout.take() {
+        let log = RotatingLog::open(out, spec.max_log_size, spec.max_log_files)?;
+        tokio::spawn(capture(stdout, log));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let log = RotatingLog::open(err, spec.max_log_size, spec.max_log_files)?;
+        tokio::spawn(capture(stderr, log));
+    }
+    Ok(child)
+}
+
+async fn capture<R: AsyncRead + Unpin>(reader: R, mut log: RotatingLog) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Err(e) = log.write_line(&line) {
+            log!("Failed to write log: {}", e);
+            return;
+        }
+    }
+}
+
+/// SIGTERM, wait `stop_timeout`, then SIGKILL.
+/// Signals are sent to the whole process group.
+async fn terminate(spec: &Spec, child: &mut Child) {
+    let pid = match child.id() {
+        Some(pid) => pid,
+        None => return,
+    };
+    signal(pid, "TERM").await;
+    match time::timeout(spec.stop_timeout, child.wait()).await {
+        Ok(_) => {
+            log!("Stopped {}", spec.name);
+        }
+        Err(_) => {
+            log!("{} did not stop in time, killing it", spec.name);
+            signal(pid, "KILL").await;
+            let _ = child.kill().await;
+        }
+    }
+}
+
+async fn signal(pid: u32, signal: &str) {
+    let _ = Command::new("kill")
+        .arg("-s")
+        .arg(signal)
+        .arg("--")
+        .arg(format!("-{}", pid))
+        .status()
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn settings(dir: &Path, stop_timeout: u64) -> SupervisorSettings {
+        SupervisorSettings {
+            log_dir: dir.to_str().unwrap().to_owned(),
+            stop_timeout,
+            ..SupervisorSettings::default()
+        }
+    }
+
+    fn service(command: &str) -> Service {
+        Service {
+            command: Some(command.to_owned()),
+            ..Service::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_and_stop() {
+        let dir = env::temp_dir().join(format!("deployer-supervisor-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let supervisor = Supervisor::default();
+        let service = service("echo started; exit 1");
+        let start = || supervisor.start(&service.name, &service, &dir, &settings(&dir, 1), vec![]);
+        start().unwrap();
+        assert!(start().is_err());
+
+        // Crashes once, restarts after a second.
+        time::sleep(Duration::from_millis(1500)).await;
+        assert!(supervisor.stop(&service.name).await);
+        assert!(supervisor.state(&service.name).is_none());
+
+        let log = fs::read_to_string(dir.join("service-name.out.log")).unwrap();
+        assert_eq!(log, "started\nstarted\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kill_after_timeout() {
+        let dir = env::temp_dir().join(format!("deployer-supervisor-kill-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let supervisor = Supervisor::default();
+        let service = service("trap '' TERM; sleep 30");
+        supervisor
+            .start(&service.name, &service, &dir, &settings(&dir, 1), vec![])
+            .unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+
+        let started = Instant::now();
+        assert!(supervisor.stop(&service.name).await);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Result, Write},
+    path::PathBuf,
+};
+
+/// Log file that is rotated once it grows past `max_size`.
+/// Rotated files get a numeric suffix (`app.out.log.1`,
+/// `app.out.log.2`, ...) and only `max_files` of them are kept.
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingLog {
+    pub fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingLog {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // app.log.4 -> app.log.5, ..., app.log -> app.log.1
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated(i);
+                if from.exists() {
+                    fs::rename(from, self.rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_rotation() {
+        let dir = env::temp_dir().join(format!("deployer-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.out.log");
+
+        let mut log = RotatingLog::open(path.clone(), 10, 2).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            log.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("app.out.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("app.out.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("app.out.log.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
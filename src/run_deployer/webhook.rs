@@ -0,0 +1,406 @@
+// Receiver of GitHub, Gitea and Forgejo `push` and `release`
+// webhooks. A verified push to the watched branch (or of a tag, or
+// a release for repositories with `track`) wakes the polling loop
+// right away, so the deploy starts without waiting for the next poll.
+
+use super::{control::Control, provider};
+use crate::generate_conf::file_struct::{RepositorySettings, TrackSource};
+use crate::log;
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::HeaderMap,
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde_derive::Deserialize;
+use sha2::Sha256;
+use std::{convert::Infallible, sync::Arc};
+use tokio::net::TcpListener;
+
+/// GitHub payloads are capped at 25 MB.
+const MAX_BODY: usize = 25 * 1024 * 1024;
+
+/// Providers a delivery can be about.
+const GITHUB: &[&str] = &["github"];
+const GITEA: &[&str] = &["gitea", "forgejo"];
+
+#[derive(Debug, Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    after: String,
+    repository: PushRepository,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseEvent {
+    action: String,
+    release: Release,
+    repository: PushRepository,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushRepository {
+    full_name: String,
+}
+
+/// Accept webhook deliveries forever.
+pub async fn serve(listener: TcpListener, control: Arc<Control>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log!("Failed to accept webhook connection: {}", e);
+                continue;
+            }
+        };
+        let control = control.clone();
+        tokio::spawn(async move {
+            let service = service_fn(|req| respond(&control, req));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                log!("Webhook connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(
+    control: &Control,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let (status, message) = match Limited::new(body, MAX_BODY).collect().await {
+        Ok(body) => handle(
+            control,
+            &parts.method,
+            parts.uri.path(),
+            &parts.headers,
+            &body.to_bytes(),
+        ),
+        Err(_) => (StatusCode::PAYLOAD_TOO_LARGE, "Payload is too large"),
+    };
+    let mut res = Response::new(Full::new(Bytes::from(message)));
+    *res.status_mut() = status;
+    Ok(res)
+}
+
+fn handle(
+    control: &Control,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> (StatusCode, &'static str) {
+    let config = control.config();
+    let settings = match &config.webhook {
+        Some(settings) => settings,
+        None => return (StatusCode::NOT_FOUND, "Webhooks are disabled"),
+    };
+    if path != settings.path {
+        return (StatusCode::NOT_FOUND, "Not found");
+    }
+    if method != Method::POST {
+        return (StatusCode::METHOD_NOT_ALLOWED, "Use POST");
+    }
+
+    let (event, signature, providers) = delivery(headers);
+    if !verify(&settings.secret, body, &signature) {
+        log!("Rejected webhook with invalid signature");
+        return (StatusCode::UNAUTHORIZED, "Invalid signature");
+    }
+
+    let matching: Vec<_> = match event {
+        "ping" => return (StatusCode::OK, "pong"),
+        "push" => {
+            let push: PushEvent = match serde_json::from_slice(body) {
+                Ok(push) => push,
+                Err(_) => return (StatusCode::BAD_REQUEST, "Invalid push payload"),
+            };
+            let matching: Vec<_> = config
+                .repositories
+                .iter()
+                .filter(|r| providers.contains(&r.provider.as_str()) && matches(r, &push))
+                .collect();
+            if matching.is_empty() {
+                return (StatusCode::OK, "Ignored push");
+            }
+            log!(
+                "Received push of {} to {} of {}",
+                push.after,
+                push.git_ref,
+                push.repository.full_name
+            );
+            matching
+        }
+        "release" => {
+            let release: ReleaseEvent = match serde_json::from_slice(body) {
+                Ok(release) => release,
+                Err(_) => return (StatusCode::BAD_REQUEST, "Invalid release payload"),
+            };
+            let matching: Vec<_> = config
+                .repositories
+                .iter()
+                .filter(|r| {
+                    providers.contains(&r.provider.as_str())
+                        && r.track.as_ref().map(|t| t.source) == Some(TrackSource::Releases)
+                        && same_repository(r, &release.repository)
+                })
+                .collect();
+            if matching.is_empty() {
+                return (StatusCode::OK, "Ignored release");
+            }
+            log!(
+                "Received release {} ({}) of {}",
+                release.release.tag_name,
+                release.action,
+                release.repository.full_name
+            );
+            matching
+        }
+        _ => return (StatusCode::OK, "Ignored event"),
+    };
+    for repository in matching {
+        control.poll_now(&repository.id());
+    }
+    (StatusCode::ACCEPTED, "Deploy triggered")
+}
+
+/// Event, signature (`sha256=<hex>`) and possible providers of a
+/// delivery. Gitea and Forgejo send GitHub's headers too, so
+/// their own are looked at first.
+fn delivery(headers: &HeaderMap) -> (&str, String, &'static [&'static str]) {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    for forge in ["Forgejo", "Gitea"] {
+        if let Some(event) = header(&format!("X-{}-Event", forge)) {
+            let signature = match header(&format!("X-{}-Signature", forge)) {
+                Some(hex) => format!("sha256={}", hex),
+                None => header("X-Hub-Signature-256").unwrap_or("").to_owned(),
+            };
+            return (event, signature, GITEA);
+        }
+    }
+    let event = header("X-GitHub-Event").unwrap_or("");
+    let signature = header("X-Hub-Signature-256").unwrap_or("").to_owned();
+    (event, signature, GITHUB)
+}
+
+/// Check `X-Hub-Signature-256` (`sha256=<hex HMAC of the body>`).
+fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let signature = match signature.strip_prefix("sha256=").map(hex::decode) {
+        Some(Ok(signature)) => signature,
+        _ => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Push is to the configured repository and branch,
+/// or of a tag if the repository tracks tags.
+fn matches(repository: &RepositorySettings, push: &PushEvent) -> bool {
+    let pushed = match repository.track.as_ref().map(|t| t.source) {
+        Some(TrackSource::Tags) => push.git_ref.starts_with("refs/tags/"),
+        Some(TrackSource::Releases) => false,
+        None => push.git_ref == format!("refs/heads/{}", repository.branch),
+    };
+    pushed && same_repository(repository, &push.repository)
+}
+
+fn same_repository(repository: &RepositorySettings, event: &PushRepository) -> bool {
+    match provider::new(repository) {
+        Ok(provider) => event.full_name.eq_ignore_ascii_case(provider.full_name()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_conf::file_struct::{ConfigFile, TrackSettings, WebhookSettings};
+
+    const BODY: &str = r#"{
+        "ref": "refs/heads/main",
+        "after": "0123456789abcdef",
+        "repository": { "full_name": "Makefolder/deployer" }
+    }"#;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn control() -> Control {
+        control_tracking(None)
+    }
+
+    fn control_tracking(track: Option<TrackSettings>) -> Control {
+        let mut config = ConfigFile {
+            repository: "github.com/Makefolder/deployer".to_owned(),
+            track,
+            webhook: Some(WebhookSettings {
+                secret: "It's a Secret to Everybody".to_owned(),
+                ..WebhookSettings::default()
+            }),
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        Control::new("", config)
+    }
+
+    fn headers(event: &str, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-GitHub-Event", event.parse().unwrap());
+        headers.insert("X-Hub-Signature-256", signature.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_verify() {
+        // Example from GitHub's documentation.
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify(
+            "It's a Secret to Everybody",
+            b"Hello, World!",
+            signature
+        ));
+        assert!(!verify("wrong secret", b"Hello, World!", signature));
+        assert!(!verify(
+            "It's a Secret to Everybody",
+            b"Hello, World!",
+            "sha256=zz"
+        ));
+        assert!(!verify("It's a Secret to Everybody", b"Hello, World!", ""));
+    }
+
+    #[test]
+    fn test_push() {
+        let control = control();
+        let signature = sign("It's a Secret to Everybody", BODY.as_bytes());
+        let headers = headers("push", &signature);
+
+        let (status, _) = handle(
+            &control,
+            &Method::POST,
+            "/webhook",
+            &headers,
+            BODY.as_bytes(),
+        );
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, _) = handle(&control, &Method::POST, "/webhook", &headers, b"{}");
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_other_branch_is_ignored() {
+        let control = control();
+        let body = BODY.replace("refs/heads/main", "refs/heads/develop");
+        let signature = sign("It's a Secret to Everybody", body.as_bytes());
+        let headers = headers("push", &signature);
+
+        let (status, message) = handle(
+            &control,
+            &Method::POST,
+            "/webhook",
+            &headers,
+            body.as_bytes(),
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(message, "Ignored push");
+    }
+
+    #[test]
+    fn test_forgejo_push() {
+        let mut config = ConfigFile {
+            repository: "git.example.com/Makefolder/deployer".to_owned(),
+            provider: Some("forgejo".to_owned()),
+            webhook: Some(WebhookSettings {
+                secret: "It's a Secret to Everybody".to_owned(),
+                ..WebhookSettings::default()
+            }),
+            ..ConfigFile::default()
+        };
+        config.normalise().unwrap();
+        let control = Control::new("", config);
+
+        let signature = sign("It's a Secret to Everybody", BODY.as_bytes());
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forgejo-Event", "push".parse().unwrap());
+        headers.insert(
+            "X-Forgejo-Signature",
+            signature.trim_start_matches("sha256=").parse().unwrap(),
+        );
+        let (status, _) = handle(
+            &control,
+            &Method::POST,
+            "/webhook",
+            &headers,
+            BODY.as_bytes(),
+        );
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // Same repository name, but the delivery is from GitHub.
+        let headers = self::headers("push", &signature);
+        let (_, message) = handle(
+            &control,
+            &Method::POST,
+            "/webhook",
+            &headers,
+            BODY.as_bytes(),
+        );
+        assert_eq!(message, "Ignored push");
+    }
+
+    #[test]
+    fn test_release_wakes_tracking_repositories() {
+        let body = r#"{
+            "action": "published",
+            "release": { "tag_name": "v1.2.0" },
+            "repository": { "full_name": "Makefolder/deployer" }
+        }"#;
+        let signature = sign("It's a Secret to Everybody", body.as_bytes());
+        let headers = headers("release", &signature);
+
+        let control = control();
+        let (_, message) = handle(
+            &control,
+            &Method::POST,
+            "/webhook",
+            &headers,
+            body.as_bytes(),
+        );
+        assert_eq!(message, "Ignored release");
+
+        let control = control_tracking(Some(TrackSettings {
+            source: TrackSource::Releases,
+            pattern: None,
+            version: None,
+            prereleases: false,
+        }));
+        let (status, _) = handle(
+            &control,
+            &Method::POST,
+            "/webhook",
+            &headers,
+            body.as_bytes(),
+        );
+        assert_eq!(status, StatusCode::ACCEPTED);
+    }
+}